@@ -0,0 +1,174 @@
+use crate::pack::Output;
+
+use super::{
+    gen_search, search_cost as tree_cost, Memory, BANK_CELL, BANK_INDEX, BANK_SIZE, CURRENT_BANK,
+    FUNC_BANK, MEM_POINTER, OUT_OF_RANGE, PREFIX, REG_R0,
+};
+
+/// Generate `name`, which runs the command produced by `generate` with the
+/// cell of the banked memory `MEM_POINTER` points to, or `fallback` if it
+/// is out of the memory. The pointer is split into its bank and its cell in
+/// the bank, every bank has a search tree of its own.
+pub fn gen_banked_search(
+    output: &mut Output,
+    memory: &Memory,
+    name: &str,
+    fallback: Option<&str>,
+    generate: &dyn Fn(&str) -> String,
+) {
+    let split = format!("{name}/Split");
+    let banks = format!("{name}/Banks");
+    let current = format!("{name}/Current");
+    let bank = |nth| format!("{name}/Bank{nth}");
+
+    let upper_bound = memory.size - 1;
+    let mut content = match fallback {
+        Some(fallback) => format!(
+            "execute unless score {PREFIX} {MEM_POINTER} matches 0..{upper_bound} run {fallback}\n"
+        ),
+        None => String::new(),
+    };
+    content += &format!(
+        "execute if score {PREFIX} {MEM_POINTER} matches 0..{upper_bound} run function {split}"
+    );
+    output.add_function(name, content);
+
+    let dispatch = match memory.current_bank {
+        true => format!(
+            "execute if score {PREFIX} {BANK_INDEX} = {PREFIX} {CURRENT_BANK} run function {current}\n\
+            execute unless score {PREFIX} {BANK_INDEX} = {PREFIX} {CURRENT_BANK} run function {banks}"
+        ),
+        false => format!("function {banks}"),
+    };
+    output.add_function(
+        &split,
+        format!(
+            "scoreboard players operation {PREFIX} {BANK_INDEX} = {PREFIX} {MEM_POINTER}\n\
+            scoreboard players operation {PREFIX} {BANK_INDEX} /= {PREFIX} {BANK_SIZE}\n\
+            scoreboard players operation {PREFIX} {BANK_CELL} = {PREFIX} {MEM_POINTER}\n\
+            scoreboard players operation {PREFIX} {BANK_CELL} %= {PREFIX} {BANK_SIZE}\n\
+            {dispatch}"
+        ),
+    );
+
+    let tree = |output: &mut Output, name: &str, size, generate: &dyn Fn(usize) -> String| {
+        gen_search(
+            output,
+            name,
+            BANK_CELL,
+            None,
+            size,
+            memory.fanout,
+            None,
+            generate,
+        )
+    };
+    gen_search(
+        output,
+        &banks,
+        BANK_INDEX,
+        None,
+        memory.banks(),
+        memory.fanout,
+        None,
+        |nth| format!("function {}", bank(nth)),
+    );
+    for nth in 0..memory.banks() {
+        let start = nth * memory.bank_size;
+        let size = memory.bank_size.min(memory.size - start);
+        tree(output, &bank(nth), size, &|cell| {
+            generate(&memory.layout.cell(start + cell))
+        });
+    }
+    if memory.current_bank {
+        tree(output, &current, memory.bank_size, &|cell| {
+            generate(&memory.layout.window_cell(cell))
+        });
+    }
+}
+
+/// Commands run by a search of the banked memory in the worst case, for a
+/// cell of the current bank if `window`.
+pub fn search_cost(memory: &Memory, window: bool) -> usize {
+    // the test at the entry, splitting the pointer and choosing the bank
+    let split = 3 + 4 + if memory.current_bank { 3 } else { 1 };
+    let bank = tree_cost(memory.bank_size, memory.fanout);
+    match window {
+        true => split + bank,
+        false => split + tree_cost(memory.banks(), memory.fanout) + bank,
+    }
+}
+
+/// Commands `FUNC_BANK` runs in the worst case, copying the window back to
+/// the current bank and the new bank into the window.
+pub fn bank_cost(memory: &Memory) -> usize {
+    7 + 2 * (tree_cost(memory.banks(), memory.fanout) + memory.bank_size)
+}
+
+/// Write `FUNC_BANK`, which makes the bank in `R0` the current one. With
+/// `checks`, it raises `OUT_OF_RANGE` for a bank that does not exist.
+pub fn gen_set_bank(output: &mut Output, memory: &Memory, checks: bool) {
+    let switch = format!("{FUNC_BANK}/Switch");
+    let flush = format!("{FUNC_BANK}/Flush");
+    let fill = format!("{FUNC_BANK}/Fill");
+
+    let upper_bound = memory.banks() - 1;
+    let check = match checks {
+        true => format!(
+            "execute unless score {PREFIX} {REG_R0} matches 0..{upper_bound} run scoreboard players operation {PREFIX} {MEM_POINTER} = {PREFIX} {REG_R0}\n\
+            execute unless score {PREFIX} {REG_R0} matches 0..{upper_bound} run {}\n",
+            OUT_OF_RANGE.raise()
+        ),
+        false => String::new(),
+    };
+    output.add_function(
+        FUNC_BANK,
+        format!(
+            "{check}\
+            execute if score {PREFIX} {REG_R0} matches 0..{upper_bound} unless score {PREFIX} {REG_R0} = {PREFIX} {CURRENT_BANK} run function {switch}\n"
+        ),
+    );
+    output.add_function(
+        &switch,
+        format!(
+            "function {flush}\n\
+            scoreboard players operation {PREFIX} {CURRENT_BANK} = {PREFIX} {REG_R0}\n\
+            function {fill}\n"
+        ),
+    );
+
+    // copy every cell of the bank between it and the window
+    let copy = |output: &mut Output, name: &str, into_window: bool| {
+        let bank = |nth| format!("{name}/Bank{nth}");
+        for nth in 0..memory.banks() {
+            let start = nth * memory.bank_size;
+            let size = memory.bank_size.min(memory.size - start);
+            let content: String = (0..size)
+                .map(|cell| {
+                    let (dst, src) = (
+                        memory.layout.cell(start + cell),
+                        memory.layout.window_cell(cell),
+                    );
+                    let (dst, src) = match into_window {
+                        true => (src, dst),
+                        false => (dst, src),
+                    };
+                    format!("scoreboard players operation {dst} = {src}\n")
+                })
+                .collect();
+            output.add_function(&bank(nth), content);
+        }
+        gen_search(
+            output,
+            name,
+            CURRENT_BANK,
+            None,
+            memory.banks(),
+            memory.fanout,
+            None,
+            |nth| format!("function {}", bank(nth)),
+        );
+    };
+    copy(output, &flush, false);
+    copy(output, &fill, true);
+}
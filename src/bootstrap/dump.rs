@@ -0,0 +1,84 @@
+use crate::pack::Output;
+
+use super::{
+    Memory, CURRENT_THREAD, DUMPED, ERROR, FUNC_DUMP, FUNC_LOAD, MEM_OFFSET, MEM_POINTER, PREFIX,
+    PROGRAM_COUNTER, REG_R0, REG_R1, REG_R2, REG_R3, STACK_POINTER,
+};
+
+/// Cells printed on every line of a dump.
+const ROW: usize = 8;
+
+/// Commands `FUNC_DUMP` runs in the worst case.
+pub fn dump_cost(memory: &Memory, size: usize) -> usize {
+    // clearing, loading, copying the cell and moving on to the next one
+    6 + size * (6 + memory.search_cost()) + size.div_ceil(ROW)
+}
+
+/// Write `FUNC_DUMP`, which prints the registers and the `size` cells
+/// starting at `MEM_POINTER` with `tellraw`. Cells out of the memory are
+/// printed as 0. The registers and `MEM_POINTER` are kept.
+pub fn gen_dump(output: &mut Output, memory: &Memory, size: usize) {
+    let in_memory = match memory.size {
+        0 => None,
+        _ => Some(format!(
+            "execute if score {PREFIX} {MEM_POINTER} matches 0..{} run",
+            memory.size - 1
+        )),
+    };
+
+    let mut content = format!(
+        "scoreboard players operation #r0 {DUMPED} = {PREFIX} {REG_R0}\n\
+        scoreboard players operation #start {DUMPED} = {PREFIX} {MEM_POINTER}\n"
+    );
+    for nth in 0..size {
+        content += &format!("scoreboard players set #{nth} {DUMPED} 0\n");
+        if let Some(in_memory) = &in_memory {
+            content += &format!(
+                "{in_memory} function {FUNC_LOAD}\n\
+                {in_memory} scoreboard players operation #{nth} {DUMPED} = {PREFIX} {REG_R0}\n"
+            );
+        }
+        content += &format!("scoreboard players add {PREFIX} {MEM_POINTER} 1\n");
+    }
+    content += &format!(
+        "scoreboard players operation {PREFIX} {REG_R0} = #r0 {DUMPED}\n\
+        scoreboard players operation {PREFIX} {MEM_POINTER} = #start {DUMPED}\n"
+    );
+
+    let registers: Vec<String> = [
+        ("R0", REG_R0),
+        ("R1", REG_R1),
+        ("R2", REG_R2),
+        ("R3", REG_R3),
+        ("pc", PROGRAM_COUNTER),
+        ("offset", MEM_OFFSET),
+        ("sp", STACK_POINTER),
+        ("thread", CURRENT_THREAD),
+        ("error", ERROR),
+    ]
+    .into_iter()
+    .map(|(name, objective)| format!(r#"{{"text":" {name}="}},{}"#, score(PREFIX, objective)))
+    .collect();
+    content += &tellraw(&format!(r#"{{"text":"[mcvm]"}},{}"#, registers.join(",")));
+
+    for row in (0..size).step_by(ROW) {
+        let cells: Vec<String> = (row..(row + ROW).min(size))
+            .map(|nth| format!(r#"{{"text":" "}},{}"#, score(&format!("#{nth}"), DUMPED)))
+            .collect();
+        content += &tellraw(&format!(
+            r#"{{"text":"[mcvm] "}},{},{{"text":"+{row}:"}},{}"#,
+            score("#start", DUMPED),
+            cells.join(",")
+        ));
+    }
+
+    output.add_function(FUNC_DUMP, content);
+}
+
+fn score(name: &str, objective: &str) -> String {
+    format!(r#"{{"score":{{"name":"{name}","objective":"{objective}"}}}}"#)
+}
+
+fn tellraw(rawtext: &str) -> String {
+    format!("tellraw @a {{\"rawtext\":[{rawtext}]}}\n")
+}
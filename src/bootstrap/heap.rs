@@ -0,0 +1,330 @@
+use const_format::formatcp;
+
+use crate::pack::Output;
+
+use super::{
+    Memory, FUNC_ALLOC, FUNC_FREE, FUNC_LOAD, FUNC_STORE, HALTED, HEAP, MEM_POINTER, OUT_OF_RANGE,
+    PREFIX, REG_R0,
+};
+
+/// First free block, 0 if the heap is full.
+const HEAD: &str = formatcp!("{PREFIX}_Head");
+// scratch values of `FUNC_ALLOC` and `FUNC_FREE`
+const NEED: &str = formatcp!("{PREFIX}_Need");
+const PTR: &str = formatcp!("{PREFIX}_Ptr");
+const PREV: &str = formatcp!("{PREFIX}_Prev");
+const CUR: &str = formatcp!("{PREFIX}_Cur");
+const NEXT: &str = formatcp!("{PREFIX}_Next");
+const SIZE: &str = formatcp!("{PREFIX}_Size");
+const FIT: &str = formatcp!("{PREFIX}_Fit");
+const END: &str = formatcp!("{PREFIX}_End");
+const FOUND: &str = formatcp!("{PREFIX}_Found");
+
+/// Commands that set up the heap of `size` cells starting at the memory
+/// cell `base` as a single free block.
+pub fn init_heap(memory: &Memory, base: usize, size: usize) -> String {
+    format!(
+        "scoreboard players set {HEAD} {HEAP} {}\n\
+        {}\n",
+        base + 1,
+        memory.set_cell(base, size - 1)
+    )
+}
+
+/// Commands `FUNC_ALLOC` runs in the worst case, walking every free block
+/// of the heap before splitting the last one, where a memory access
+/// searches with `search` commands.
+pub fn alloc_cost(search: usize, heap_size: usize) -> usize {
+    9 + heap_size / 2 * (15 + 2 * search) + 26 + 5 * search
+}
+
+/// Commands `FUNC_FREE` runs in the worst case, walking every free block
+/// of the heap before merging the block with both neighbors.
+pub fn free_cost(search: usize, heap_size: usize) -> usize {
+    68 + 12 * search + heap_size / 2 * (6 + search)
+}
+
+/// Write `FUNC_ALLOC` and `FUNC_FREE`, managing the `size` cells of memory
+/// starting at `base`. Every block is preceded by a cell holding its size,
+/// free blocks hold the next free block in their first cell, in the order
+/// of their addresses.
+///
+/// `FUNC_ALLOC` takes the size in `R0` and leaves the first free block
+/// large enough for it in `R0`, or 0 if there is none. `FUNC_FREE` gives
+/// back the block in `R0`, merging it with the free blocks around it. With
+/// `checks`, it raises `OUT_OF_RANGE` for a pointer outside of the heap.
+pub fn gen_heap(output: &mut Output, base: usize, size: usize, checks: bool) {
+    let alloc_next = format!("{FUNC_ALLOC}/Next");
+    let alloc_skip = format!("{FUNC_ALLOC}/Skip");
+    let alloc_take = format!("{FUNC_ALLOC}/Take");
+    let alloc_split = format!("{FUNC_ALLOC}/Split");
+    let free_block = format!("{FUNC_FREE}/Block");
+    let free_walk = format!("{FUNC_FREE}/Walk");
+    let merge_next = format!("{FUNC_FREE}/MergeNext");
+    let merge_prev = format!("{FUNC_FREE}/MergePrev");
+    let merge_into = format!("{FUNC_FREE}/MergeInto");
+
+    let set = |dst: &str, src: &str| {
+        format!("scoreboard players operation {dst} {HEAP} = {src} {HEAP}\n")
+    };
+    let from_r0 =
+        |dst: &str| format!("scoreboard players operation {dst} {HEAP} = {PREFIX} {REG_R0}\n");
+    let to_r0 =
+        |src: &str| format!("scoreboard players operation {PREFIX} {REG_R0} = {src} {HEAP}\n");
+    let point =
+        |src: &str| format!("scoreboard players operation {PREFIX} {MEM_POINTER} = {src} {HEAP}\n");
+    let header = format!("scoreboard players remove {PREFIX} {MEM_POINTER} 1\n");
+    let load = format!("function {FUNC_LOAD}\n");
+    let store = format!("function {FUNC_STORE}\n");
+
+    output.add_function(
+        FUNC_ALLOC,
+        format!(
+            "{}\
+            execute if score {NEED} {HEAP} matches ..0 run scoreboard players set {NEED} {HEAP} 1\n\
+            scoreboard players set {PREV} {HEAP} 0\n\
+            {}\
+            scoreboard players set {FOUND} {HEAP} 0\n\
+            execute unless score {CUR} {HEAP} matches 0 run function {alloc_next}\n\
+            {}",
+            from_r0(NEED),
+            set(CUR, HEAD),
+            to_r0(FOUND)
+        ),
+    );
+    // the size of the block left over after taking `NEED` cells
+    output.add_function(
+        &alloc_next,
+        format!(
+            "{}{header}{load}{}\
+            scoreboard players operation {FIT} {HEAP} -= {NEED} {HEAP}\n\
+            execute if score {FIT} {HEAP} matches 0.. run function {alloc_take}\n\
+            execute if score {FIT} {HEAP} matches ..-1 run function {alloc_skip}\n",
+            point(CUR),
+            from_r0(FIT)
+        ),
+    );
+    output.add_function(
+        &alloc_skip,
+        format!(
+            "{}{}{load}{}\
+            execute unless score {CUR} {HEAP} matches 0 run function {alloc_next}\n",
+            set(PREV, CUR),
+            point(CUR),
+            from_r0(CUR)
+        ),
+    );
+    // a rest too small for a block stays with the taken one
+    output.add_function(
+        &alloc_take,
+        format!(
+            "{}{load}{}\
+            execute if score {FIT} {HEAP} matches 2.. run function {alloc_split}\n\
+            {}\
+            execute if score {PREV} {HEAP} matches 0 run scoreboard players operation {HEAD} {HEAP} = {NEXT} {HEAP}\n\
+            execute unless score {PREV} {HEAP} matches 0 run scoreboard players operation {PREFIX} {MEM_POINTER} = {PREV} {HEAP}\n\
+            execute unless score {PREV} {HEAP} matches 0 run function {FUNC_STORE}\n\
+            {}",
+            point(CUR),
+            from_r0(NEXT),
+            to_r0(NEXT),
+            set(FOUND, CUR)
+        ),
+    );
+    output.add_function(
+        &alloc_split,
+        format!(
+            "{}\
+            scoreboard players remove {PREFIX} {REG_R0} 1\n\
+            {}\
+            scoreboard players operation {PREFIX} {MEM_POINTER} += {NEED} {HEAP}\n\
+            {store}{}\
+            scoreboard players add {PREFIX} {MEM_POINTER} 1\n\
+            {store}\
+            scoreboard players operation {NEXT} {HEAP} = {PREFIX} {MEM_POINTER}\n\
+            {}{}{header}{store}",
+            to_r0(FIT),
+            point(CUR),
+            to_r0(NEXT),
+            to_r0(NEED),
+            point(CUR)
+        ),
+    );
+
+    let check = match checks {
+        true => format!(
+            "{}\
+            execute unless score {PTR} {HEAP} matches 0 unless score {PTR} {HEAP} matches {}..{} run {}\n",
+            point(PTR),
+            base + 1,
+            base + size - 1,
+            OUT_OF_RANGE.raise()
+        ),
+        false => String::new(),
+    };
+    output.add_function(
+        FUNC_FREE,
+        format!(
+            "{}{check}\
+            execute if score {PREFIX} {HALTED} matches 0 unless score {PTR} {HEAP} matches 0 run function {free_block}\n\
+            {}",
+            from_r0(PTR),
+            to_r0(PTR)
+        ),
+    );
+    // the block goes between the free blocks `PREV` and `CUR`
+    output.add_function(
+        &free_block,
+        format!(
+            "scoreboard players set {PREV} {HEAP} 0\n\
+            {}\
+            execute unless score {CUR} {HEAP} matches 0 if score {CUR} {HEAP} < {PTR} {HEAP} run function {free_walk}\n\
+            {}{}{store}{}\
+            execute if score {PREV} {HEAP} matches 0 run scoreboard players operation {HEAD} {HEAP} = {PTR} {HEAP}\n\
+            execute unless score {PREV} {HEAP} matches 0 run scoreboard players operation {PREFIX} {MEM_POINTER} = {PREV} {HEAP}\n\
+            execute unless score {PREV} {HEAP} matches 0 run function {FUNC_STORE}\n\
+            {}{header}{load}{}{}\
+            scoreboard players operation {END} {HEAP} += {SIZE} {HEAP}\n\
+            scoreboard players add {END} {HEAP} 1\n\
+            execute unless score {CUR} {HEAP} matches 0 if score {END} {HEAP} = {CUR} {HEAP} run function {merge_next}\n\
+            execute unless score {PREV} {HEAP} matches 0 run function {merge_prev}\n",
+            set(CUR, HEAD),
+            to_r0(CUR),
+            point(PTR),
+            to_r0(PTR),
+            point(PTR),
+            from_r0(SIZE),
+            set(END, PTR)
+        ),
+    );
+    output.add_function(
+        &free_walk,
+        format!(
+            "{}{}{load}{}\
+            execute unless score {CUR} {HEAP} matches 0 if score {CUR} {HEAP} < {PTR} {HEAP} run function {free_walk}\n",
+            set(PREV, CUR),
+            point(CUR),
+            from_r0(CUR)
+        ),
+    );
+    output.add_function(
+        &merge_next,
+        format!(
+            "{}{header}{load}\
+            scoreboard players operation {SIZE} {HEAP} += {PREFIX} {REG_R0}\n\
+            scoreboard players add {SIZE} {HEAP} 1\n\
+            {}{}{header}{store}{}{load}{}{store}",
+            point(CUR),
+            to_r0(SIZE),
+            point(PTR),
+            point(CUR),
+            point(PTR)
+        ),
+    );
+    output.add_function(
+        &merge_prev,
+        format!(
+            "{}{header}{load}{}{}\
+            scoreboard players operation {END} {HEAP} += {PREV} {HEAP}\n\
+            scoreboard players add {END} {HEAP} 1\n\
+            execute if score {END} {HEAP} = {PTR} {HEAP} run function {merge_into}\n",
+            point(PREV),
+            from_r0(SIZE),
+            set(END, SIZE)
+        ),
+    );
+    output.add_function(
+        &merge_into,
+        format!(
+            "{}{header}{load}\
+            scoreboard players operation {PREFIX} {REG_R0} += {SIZE} {HEAP}\n\
+            scoreboard players add {PREFIX} {REG_R0} 1\n\
+            {}{header}{store}{}{load}{}{store}",
+            point(PTR),
+            point(PREV),
+            point(PTR),
+            point(PREV)
+        ),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{bootstrap::ERROR, emu, manifest::BuildConfig};
+
+    /// The first cells of memory after running `source` with a heap of 10
+    /// cells at the end of 40 cells, which makes 31 the first block.
+    fn run(source: &str, checks: bool, cells: usize) -> (Vec<i32>, emu::Emulator) {
+        let config = BuildConfig {
+            memory_size: 40,
+            heap_size: 10,
+            checks,
+            ..Default::default()
+        };
+        let emu = emu::run(source, &config, 10);
+        let memory = config.memory();
+        let values = (0..cells).map(|nth| emu.cell(&memory, nth)).collect();
+        (values, emu)
+    }
+
+    #[test]
+    fn reuses_freed_blocks() {
+        let source = "main:\n\
+            set R0 3\nalloc\nstore 0\n\
+            set R0 3\nalloc\nstore 1\n\
+            load 0\nfree\n\
+            set R0 2\nalloc\nstore 2\n\
+            ret\n";
+        for checks in [false, true] {
+            let (cells, emu) = run(source, checks, 3);
+            assert_eq!(cells, [31, 35, 31], "checks {checks}");
+            assert_eq!(emu.reg(ERROR), 0, "checks {checks}");
+        }
+    }
+
+    #[test]
+    fn merges_freed_blocks() {
+        // the second block leaves a block of a single cell behind it, all
+        // three merge into one in either order
+        for (first, second) in [(0, 1), (1, 0)] {
+            let source = format!(
+                "main:\n\
+                set R0 3\nalloc\nstore 0\n\
+                set R0 3\nalloc\nstore 1\n\
+                load {first}\nfree\nload {second}\nfree\n\
+                set R0 9\nalloc\nstore 2\n\
+                ret\n"
+            );
+            let (cells, _) = run(&source, true, 3);
+            assert_eq!(cells, [31, 35, 31], "freeing {first} first");
+        }
+    }
+
+    #[test]
+    fn fails_when_full() {
+        let source = "main:\n\
+            set R0 10\nalloc\nstore 0\n\
+            set R0 9\nalloc\nstore 1\n\
+            set R0 1\nalloc\nstore 2\n\
+            ret\n";
+        for checks in [false, true] {
+            let (cells, emu) = run(source, checks, 3);
+            assert_eq!(cells, [0, 31, 0], "checks {checks}");
+            assert_eq!(emu.reg(ERROR), 0, "checks {checks}");
+        }
+    }
+
+    #[test]
+    fn rejects_pointers_outside_of_the_heap() {
+        // freeing 0 does nothing, like the result of a failed `alloc`
+        let (_, emu) = run("main:\nset R0 0\nfree\nret\n", true, 0);
+        assert_eq!(emu.reg(ERROR), 0);
+
+        let source = "main:\nset R0 5\nfree\nset R0 1\nstore 0\nret\n";
+        let (cells, emu) = run(source, true, 1);
+        assert_eq!(emu.reg(ERROR), 1);
+        assert_eq!(emu.said, ["mcvm fatal error: pointer out of range"]);
+        // the program stops at the trap
+        assert_eq!(cells, [0]);
+    }
+}
@@ -1,79 +1,570 @@
-use std::{borrow::Cow, fs, path::Path};
+use std::{borrow::Cow, str::FromStr};
 
-use anyhow::Result;
+use anyhow::{anyhow, Error};
 use const_format::formatcp;
+use serde::Deserialize;
 
-pub use bin_search::gen_bin_search;
+use crate::pack::{stable_hash, Output};
 
-mod bin_search;
+pub use bank::bank_cost;
+pub use dump::dump_cost;
+pub use heap::{alloc_cost, free_cost};
+pub use search::{gen_search, search_cost, DISPATCH_FANOUT};
+
+mod bank;
+mod dump;
+mod heap;
+mod search;
 
 pub const PREFIX: &str = "MCVM_Memory";
 pub const MEM_POINTER: &str = formatcp!("{PREFIX}_Pointer");
 pub const MEM_OFFSET: &str = formatcp!("{PREFIX}_Offset");
 pub const PROGRAM_COUNTER: &str = formatcp!("{PREFIX}_Pc");
+/// First memory cell above the current frame and the values pushed onto
+/// the stack.
+pub const STACK_POINTER: &str = formatcp!("{PREFIX}_Sp");
+/// 1 once the program has stopped, so the commands still pending in the
+/// functions it was called from do nothing.
+pub const HALTED: &str = formatcp!("{PREFIX}_Halted");
 pub const REG_R0: &str = formatcp!("{PREFIX}_Reg0");
 pub const REG_R1: &str = formatcp!("{PREFIX}_Reg1");
 pub const REG_R2: &str = formatcp!("{PREFIX}_Reg2");
 pub const REG_R3: &str = formatcp!("{PREFIX}_Reg3");
+/// Scratch register holding the result of a comparison.
+pub const REG_COND: &str = formatcp!("{PREFIX}_Cond");
+/// Ticks left until a sleeping program resumes.
+pub const TIMER: &str = formatcp!("{PREFIX}_Timer");
+/// Commands the program has been charged for since it started or resumed.
+pub const BUDGET: &str = formatcp!("{PREFIX}_Budget");
+/// Code of the first trap since the error was last reset, 0 if none.
+pub const ERROR: &str = formatcp!("{PREFIX}_Error");
+/// `PROGRAM_COUNTER` when the last trap happened.
+pub const FAULT_PC: &str = formatcp!("{PREFIX}_FaultPc");
+/// `MEM_POINTER` when the last trap happened, the address that was out of
+/// range.
+pub const FAULT_ADDR: &str = formatcp!("{PREFIX}_FaultAddr");
+/// Index of the thread whose state is loaded, 0 for the entry labels.
+pub const CURRENT_THREAD: &str = formatcp!("{PREFIX}_ThreadId");
+/// Thread the scheduler starts with in the current tick.
+pub const TURN: &str = formatcp!("{PREFIX}_Turn");
+/// Objectives every thread keeps its own value of.
+pub const THREAD_STATE: [&str; 8] = [
+    PROGRAM_COUNTER,
+    TIMER,
+    MEM_OFFSET,
+    STACK_POINTER,
+    REG_R0,
+    REG_R1,
+    REG_R2,
+    REG_R3,
+];
 pub const FUNC_LOAD: &str = formatcp!("{PREFIX}_Load");
 pub const FUNC_STORE: &str = formatcp!("{PREFIX}_Store");
 pub const FUNC_SWAP: &str = formatcp!("{PREFIX}_Swap");
 pub const FUNC_EXEC: &str = formatcp!("{PREFIX}_Exec");
+/// Calls the label whose id is in `PROGRAM_COUNTER`, for `icall`.
+pub const FUNC_ICALL: &str = formatcp!("{PREFIX}_Icall");
+/// Records the error whose code is in `REG_COND` and stops the program.
+pub const FUNC_TRAP: &str = formatcp!("{PREFIX}_Trap");
+pub const FUNC_SPAWN: &str = formatcp!("{PREFIX}_Spawn");
+/// Takes a block of the heap, whose size is in `R0`.
+pub const FUNC_ALLOC: &str = formatcp!("{PREFIX}_Alloc");
+/// Gives back the block of the heap in `R0`.
+pub const FUNC_FREE: &str = formatcp!("{PREFIX}_Free");
+/// Makes the bank in `R0` the current bank.
+pub const FUNC_BANK: &str = formatcp!("{PREFIX}_SetBank");
+/// Prints the registers and the memory from `MEM_POINTER` on.
+pub const FUNC_DUMP: &str = formatcp!("{PREFIX}_Dump");
+pub const FUNC_TICK: &str = formatcp!("{PREFIX}_Tick");
+pub const FUNC_HOOKS: &str = formatcp!("{PREFIX}_Hooks");
+pub const FUNC_BOOT: &str = formatcp!("{PREFIX}_Boot");
+pub const FUNC_INIT: &str = "init";
+/// Removes every objective `FUNC_INIT` adds, and stops the hooks.
+pub const FUNC_UNINSTALL: &str = formatcp!("{PREFIX}_Uninstall");
+/// Stops every thread and clears the registers, keeping the memory.
+pub const FUNC_RESET_CPU: &str = formatcp!("{PREFIX}_ResetCpu");
+/// Stamp of the memory layout and the heap once `FUNC_INIT` has run, so
+/// running it again keeps a heap that has not changed.
+pub const INSTALLED: &str = formatcp!("{PREFIX}_Installed");
+/// 0 once `FUNC_INIT` has run, 1 once `FUNC_BOOT` has run the `@on_load`
+/// labels. The hooks do nothing while it is missing.
+pub const BOOTED: &str = formatcp!("{PREFIX}_Booted");
+/// Ticks since every `@every` label last ran, one fake player per label.
+pub const EVERY: &str = formatcp!("{PREFIX}_Every");
+/// State of the heap allocator, one fake player per value.
+pub const HEAP: &str = formatcp!("{PREFIX}_Heap");
+/// Objective holding every memory cell with `MemoryLayout::Players`.
+pub const MEM: &str = formatcp!("{PREFIX}_Mem");
+/// Bank whose cells are in the window, with `Memory::current_bank`.
+pub const CURRENT_BANK: &str = formatcp!("{PREFIX}_Bank");
+/// Bank of `MEM_POINTER` while searching a banked memory.
+pub const BANK_INDEX: &str = formatcp!("{PREFIX}_BankIndex");
+/// Cell of `MEM_POINTER` in its bank while searching a banked memory.
+pub const BANK_CELL: &str = formatcp!("{PREFIX}_BankCell");
+/// Cells of every bank, to split `MEM_POINTER` with.
+pub const BANK_SIZE: &str = formatcp!("{PREFIX}_BankSize");
+/// Values printed by `FUNC_DUMP`, one fake player per value.
+pub const DUMPED: &str = formatcp!("{PREFIX}_Dumped");
 
-pub fn generate_module_memory(function_dir: &Path, size: usize) -> Result<()> {
-    gen_bin_search(function_dir, FUNC_LOAD, MEM_POINTER, size, |nth| {
-        format!(
-            "scoreboard players operation {PREFIX} {REG_R0} = {PREFIX} {}",
-            nth_mem_name(nth)
-        )
-    })?;
+/// How the memory cells are stored in the scoreboard.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MemoryLayout {
+    /// One objective per cell, `PREFIX` on `MCVM_Memory_Mem<n>`.
+    #[default]
+    Objectives,
+    /// One fake player per cell, `#m<n>` on the single objective `MEM`.
+    Players,
+}
 
-    gen_bin_search(function_dir, FUNC_STORE, MEM_POINTER, size, |nth| {
-        format!(
-            "scoreboard players operation {PREFIX} {} = {PREFIX} {REG_R0}",
-            nth_mem_name(nth)
-        )
-    })?;
+impl MemoryLayout {
+    /// The fake player and objective of the `nth` memory cell.
+    pub fn cell(self, nth: usize) -> String {
+        match self {
+            MemoryLayout::Objectives => format!("{PREFIX} {}", nth_mem_name(nth)),
+            MemoryLayout::Players => format!("#m{nth} {MEM}"),
+        }
+    }
+
+    /// The fake player and objective of the `nth` cell of the window.
+    pub fn window_cell(self, nth: usize) -> String {
+        match self {
+            MemoryLayout::Objectives => format!("{PREFIX} {}", nth_window_name(nth)),
+            MemoryLayout::Players => format!("#w{nth} {MEM}"),
+        }
+    }
+}
+
+impl FromStr for MemoryLayout {
+    type Err = Error;
 
-    gen_bin_search(function_dir, FUNC_SWAP, MEM_POINTER, size, |nth| {
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "objectives" => Ok(MemoryLayout::Objectives),
+            "players" => Ok(MemoryLayout::Players),
+            _ => Err(anyhow!(
+                "memory layout must be `objectives` or `players`, found `{s}`"
+            )),
+        }
+    }
+}
+
+/// Shape of the memory.
+#[derive(Debug, Clone, Copy)]
+pub struct Memory {
+    pub layout: MemoryLayout,
+    pub size: usize,
+    /// Branches of the search trees.
+    pub fanout: usize,
+    /// Cells of every bank but the last one, 0 for a memory without banks.
+    pub bank_size: usize,
+    /// Whether the cells of `CURRENT_BANK` are moved into a window of their
+    /// own, which is searched without searching for the bank first.
+    pub current_bank: bool,
+}
+
+impl Memory {
+    /// Banks of a banked memory.
+    pub fn banks(&self) -> usize {
+        self.size.div_ceil(self.bank_size)
+    }
+
+    /// Commands run by an access searching for its cell in the worst case.
+    pub fn search_cost(&self) -> usize {
+        match self.bank_size {
+            0 => search_cost(self.size, self.fanout),
+            _ => bank::search_cost(self, false),
+        }
+    }
+
+    /// Commands run by an access searching for a cell of the current bank
+    /// in the worst case.
+    pub fn window_cost(&self) -> usize {
+        bank::search_cost(self, true)
+    }
+
+    /// Commands run by an access to a cell known at compile time.
+    pub fn direct_cost(&self) -> usize {
+        match self.current_bank {
+            // one test runs its command, the other does not
+            true => 3,
+            false => 1,
+        }
+    }
+
+    /// Copy the `nth` memory cell into `R0`.
+    pub fn load_cell(&self, nth: usize) -> String {
+        self.with_cell(nth, |cell| {
+            format!("scoreboard players operation {PREFIX} {REG_R0} = {cell}")
+        })
+    }
+
+    /// Copy `R0` into the `nth` memory cell.
+    pub fn store_cell(&self, nth: usize) -> String {
+        self.with_cell(nth, |cell| {
+            format!("scoreboard players operation {cell} = {PREFIX} {REG_R0}")
+        })
+    }
+
+    /// Set the `nth` memory cell to `value`.
+    pub fn set_cell(&self, nth: usize, value: usize) -> String {
+        self.with_cell(nth, |cell| format!("scoreboard players set {cell} {value}"))
+    }
+
+    /// Lines running `command` with the `nth` cell, which is in the window
+    /// while its bank is the current one.
+    fn with_cell(&self, nth: usize, command: impl Fn(&str) -> String) -> String {
+        if !self.current_bank {
+            return command(&self.layout.cell(nth));
+        }
+
+        let bank = nth / self.bank_size;
         format!(
-            "scoreboard players operation {PREFIX} {} >< {PREFIX} {REG_R0}",
-            nth_mem_name(nth)
+            "execute if score {PREFIX} {CURRENT_BANK} matches {bank} run {}\n\
+            execute unless score {PREFIX} {CURRENT_BANK} matches {bank} run {}",
+            command(&self.layout.window_cell(nth % self.bank_size)),
+            command(&self.layout.cell(nth))
         )
-    })?;
+    }
+}
 
-    init_memory(function_dir, "init", size)?;
+/// Runtime error, raised by running its function.
+#[derive(Clone, Copy)]
+pub struct Trap {
+    pub function: &'static str,
+    /// Value of `ERROR` once raised.
+    pub code: i32,
+    pub message: &'static str,
+}
 
-    Ok(())
+impl Trap {
+    pub fn raise(self) -> String {
+        format!("function {}", self.function)
+    }
 }
 
+/// A memory address out of the memory, only checked in debug builds.
+pub const OUT_OF_RANGE: Trap = Trap {
+    function: formatcp!("{PREFIX}_OutOfRange"),
+    code: 1,
+    message: "pointer out of range",
+};
+/// `icall` of an id that is not a label taken by `addr`.
+pub const BAD_POINTER: Trap = Trap {
+    function: formatcp!("{PREFIX}_BadPointer"),
+    code: 2,
+    message: "invalid function pointer",
+};
+/// A frame past the memory of the thread, only checked in debug builds.
+pub const STACK_OVERFLOW: Trap = Trap {
+    function: formatcp!("{PREFIX}_StackOverflow"),
+    code: 3,
+    message: "stack overflow",
+};
+/// Resuming a block id that does not exist, only checked in debug builds.
+pub const BAD_PC: Trap = Trap {
+    function: formatcp!("{PREFIX}_BadPc"),
+    code: 4,
+    message: "invalid program counter",
+};
+pub const TRAPS: [Trap; 4] = [OUT_OF_RANGE, BAD_POINTER, STACK_OVERFLOW, BAD_PC];
+
+/// Write the memory and its search functions, with a heap in the last
+/// `heap_size` cells if there are any, and a dump of `dump_size` cells.
+/// Out-of-range addresses raise `OUT_OF_RANGE` if `checks` is set, and are
+/// ignored otherwise.
+pub fn generate_module_memory(
+    output: &mut Output,
+    memory: &Memory,
+    heap_size: usize,
+    dump_size: usize,
+    threads: usize,
+    checks: bool,
+) {
+    let fallback = checks.then(|| OUT_OF_RANGE.raise());
+    let fallback = fallback.as_deref();
+    let load = |cell: &str| format!("scoreboard players operation {PREFIX} {REG_R0} = {cell}");
+    let store = |cell: &str| format!("scoreboard players operation {cell} = {PREFIX} {REG_R0}");
+    let swap = |cell: &str| format!("scoreboard players operation {cell} >< {PREFIX} {REG_R0}");
+    let mut search = |name, generate: &dyn Fn(&str) -> String| match memory.bank_size {
+        0 => gen_search(
+            output,
+            name,
+            MEM_POINTER,
+            None,
+            memory.size,
+            memory.fanout,
+            fallback,
+            |nth| generate(&memory.layout.cell(nth)),
+        ),
+        _ => bank::gen_banked_search(output, memory, name, fallback, generate),
+    };
+    search(FUNC_LOAD, &load);
+    search(FUNC_STORE, &store);
+    search(FUNC_SWAP, &swap);
+
+    if memory.current_bank {
+        bank::gen_set_bank(output, memory, checks);
+    }
+    dump::gen_dump(output, memory, dump_size);
+
+    let mut heap = String::new();
+    if heap_size > 0 {
+        let base = memory.size - heap_size;
+        heap::gen_heap(output, base, heap_size, checks);
+        heap = heap::init_heap(memory, base, heap_size);
+    }
+
+    let objectives = objectives(memory);
+    init_memory(output, memory, &objectives, heap, stamp(memory, heap_size));
+    uninstall(output, &objectives);
+    reset_cpu(output, threads);
+}
+
+/// Fake player holding the state of the `nth` thread while it is not
+/// running. The running thread, and the entry thread 0, use `PREFIX`.
+pub fn thread_player(nth: usize) -> String {
+    format!("{PREFIX}_Thread{nth}")
+}
+
+/// Objective of the `nth` memory cell with `MemoryLayout::Objectives`.
 fn nth_mem_name(nth: usize) -> String {
     format!("{PREFIX}_Mem{nth}")
 }
 
-fn init_memory(func_path: &Path, cmd_name: &str, size: usize) -> std::io::Result<()> {
-    // clear untracked scoreboards
-    let mut content = format!("scoreboard players reset {PREFIX}\n");
+/// Objective of the `nth` cell of the window with `MemoryLayout::Objectives`.
+fn nth_window_name(nth: usize) -> String {
+    format!("{PREFIX}_Window{nth}")
+}
 
-    for name in (0..size).map(|name| Cow::Owned(nth_mem_name(name))).chain(
-        [
-            MEM_POINTER,
-            MEM_OFFSET,
-            PROGRAM_COUNTER,
-            REG_R0,
-            REG_R1,
-            REG_R2,
-            REG_R3,
-        ]
-        .into_iter()
-        .map(Cow::Borrowed),
-    ) {
+/// Value of `INSTALLED` for `memory` with a heap of `heap_size` cells at its
+/// end, which changes with anything the heap's free list depends on.
+fn stamp(memory: &Memory, heap_size: usize) -> i32 {
+    let layout = format!(
+        "{:?} {} {} {} {heap_size}",
+        memory.layout, memory.size, memory.bank_size, memory.current_bank
+    );
+    // 0 is the value of a missing score
+    (stable_hash(&layout, "installed") % i32::MAX as u64) as i32 + 1
+}
+
+/// Every objective the build creates.
+fn objectives(memory: &Memory) -> Vec<Cow<'static, str>> {
+    let window = match memory.current_bank {
+        true => memory.bank_size,
+        false => 0,
+    };
+    let (cells, window_cells) = match memory.layout {
+        MemoryLayout::Objectives => (memory.size, window),
+        MemoryLayout::Players => (0, 0),
+    };
+    let cells = (0..cells).map(|name| Cow::Owned(nth_mem_name(name)));
+    let window_cells = (0..window_cells).map(|name| Cow::Owned(nth_window_name(name)));
+    let mem = match memory.layout {
+        MemoryLayout::Objectives => None,
+        MemoryLayout::Players => Some(MEM),
+    };
+    cells
+        .chain(window_cells)
+        .chain(
+            [
+                MEM_POINTER,
+                MEM_OFFSET,
+                STACK_POINTER,
+                PROGRAM_COUNTER,
+                HALTED,
+                REG_R0,
+                REG_R1,
+                REG_R2,
+                REG_R3,
+                REG_COND,
+                TIMER,
+                BUDGET,
+                ERROR,
+                FAULT_PC,
+                FAULT_ADDR,
+                CURRENT_THREAD,
+                TURN,
+                EVERY,
+                HEAP,
+                CURRENT_BANK,
+                BANK_INDEX,
+                BANK_CELL,
+                BANK_SIZE,
+                DUMPED,
+                BOOTED,
+                INSTALLED,
+            ]
+            .into_iter()
+            .chain(mem)
+            .map(Cow::Borrowed),
+        )
+        .collect()
+}
+
+/// Write `FUNC_INIT`, which adds the objectives and sets up the heap the
+/// first time it runs. Running it again keeps the memory and the state of
+/// the program, and only gives the scores added by a newer build a value.
+/// The heap is set up again if `stamp` differs from the installed one.
+fn init_memory(
+    output: &mut Output,
+    memory: &Memory,
+    objectives: &[Cow<str>],
+    heap: String,
+    stamp: i32,
+) {
+    // adding an existing objective fails without doing anything, and adding
+    // 0 to a score only creates it if it is missing
+    let mut content = String::new();
+    for name in objectives {
+        content += &format!(
+            "scoreboard objectives add {name} dummy\n\
+            scoreboard players add {PREFIX} {name} 0\n"
+        );
+    }
+    if memory.layout == MemoryLayout::Players {
+        let window = match memory.current_bank {
+            true => memory.bank_size,
+            false => 0,
+        };
+        for nth in 0..memory.size {
+            content += &format!("scoreboard players add {} 0\n", memory.layout.cell(nth));
+        }
+        for nth in 0..window {
+            content += &format!(
+                "scoreboard players add {} 0\n",
+                memory.layout.window_cell(nth)
+            );
+        }
+    }
+    if memory.bank_size > 0 {
+        content += &format!(
+            "scoreboard players set {PREFIX} {BANK_SIZE} {}\n",
+            memory.bank_size
+        );
+    }
+    if !heap.is_empty() {
+        let init_heap = format!("{FUNC_INIT}/Heap");
         content += &format!(
-            "scoreboard objectives add {0} dummy\n\
-            scoreboard players set {PREFIX} {0} 0\n",
-            name
+            "execute unless score {PREFIX} {INSTALLED} matches {stamp} run function {init_heap}\n"
         );
+        output.add_function(&init_heap, heap);
     }
+    content += &format!("scoreboard players set {PREFIX} {INSTALLED} {stamp}\n");
 
-    fs::write(func_path.join(format!("{cmd_name}.mcfunction")), content)
+    output.add_function(FUNC_INIT, content);
+}
+
+/// Write `FUNC_UNINSTALL`, which removes every objective, and with them
+/// the memory and the state of every thread. Without `BOOTED` the hooks
+/// stop until `FUNC_INIT` runs again.
+fn uninstall(output: &mut Output, objectives: &[Cow<str>]) {
+    let content: String = objectives
+        .iter()
+        .map(|name| format!("scoreboard objectives remove {name}\n"))
+        .collect();
+    output.add_function(FUNC_UNINSTALL, content);
+}
+
+/// Write `FUNC_RESET_CPU`, which stops every thread and clears the
+/// registers and the last error. The memory, the heap and the current bank
+/// are kept.
+fn reset_cpu(output: &mut Output, threads: usize) {
+    let mut content: String = THREAD_STATE
+        .into_iter()
+        .chain([
+            MEM_POINTER,
+            REG_COND,
+            BUDGET,
+            ERROR,
+            FAULT_PC,
+            FAULT_ADDR,
+            CURRENT_THREAD,
+            TURN,
+        ])
+        .map(|name| format!("scoreboard players set {PREFIX} {name} 0\n"))
+        .collect();
+    for nth in 1..threads {
+        content += &format!("scoreboard players reset {}\n", thread_player(nth));
+    }
+    // the commands still pending in the program do nothing
+    content += &format!("scoreboard players set {PREFIX} {HALTED} 1\n");
+    output.add_function(FUNC_RESET_CPU, content);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        bootstrap::{BOOTED, FUNC_HOOKS, FUNC_INIT, FUNC_UNINSTALL, PREFIX, REG_R0, REG_R1},
+        emu::{self, Emulator},
+        manifest::BuildConfig,
+    };
+
+    #[test]
+    fn uninstall_stops_the_hooks() {
+        let source = "@on_tick count:\nload 0\nset R1 1\ncalc +\nstore 0\nret\n";
+        let config = BuildConfig {
+            entries: Vec::new(),
+            ..Default::default()
+        };
+        let memory = config.memory();
+        let mut emu = Emulator::new(emu::compile(source, &config));
+        // nothing runs before the first `init`
+        emu.function(FUNC_HOOKS);
+        assert_eq!(emu.score(PREFIX, BOOTED), None);
+
+        emu.function(FUNC_INIT);
+        emu.function(FUNC_HOOKS);
+        emu.function(FUNC_HOOKS);
+        assert_eq!(emu.cell(&memory, 0), 2);
+
+        emu.function(FUNC_UNINSTALL);
+        emu.function(FUNC_HOOKS);
+        assert_eq!(emu.score(PREFIX, REG_R0), None);
+        assert_eq!(emu.score(PREFIX, BOOTED), None);
+        assert_eq!(emu.cell(&memory, 0), 0);
+
+        // installing again starts them over
+        emu.function(FUNC_INIT);
+        emu.function(FUNC_HOOKS);
+        assert_eq!(emu.cell(&memory, 0), 1);
+    }
+
+    #[test]
+    fn labels_may_be_named_like_the_old_functions() {
+        let source = "uninstall:\ncall 0 reset_cpu\nret\n\nreset_cpu:\nset R1 7\nret\n";
+        let config = BuildConfig {
+            entries: vec!["uninstall".to_string()],
+            ..Default::default()
+        };
+        let mut emu = Emulator::new(emu::compile(source, &config));
+        emu.function(FUNC_INIT);
+        emu.function("uninstall");
+        assert_eq!(emu.reg(REG_R1), 7);
+    }
+
+    #[test]
+    fn init_sets_up_a_changed_heap_again() {
+        let source = "main:\nset R0 3\nalloc\nstore 0\nret\n";
+        let build = |heap_size| BuildConfig {
+            memory_size: 40,
+            heap_size,
+            ..Default::default()
+        };
+
+        let config = build(10);
+        let mut emu = emu::run(source, &config, 10);
+        assert_eq!(emu.cell(&config.memory(), 0), 31);
+        // running `init` again keeps the block allocated
+        emu.function(FUNC_INIT);
+        emu.function("main");
+        assert_eq!(emu.cell(&config.memory(), 0), 35);
+
+        // a larger heap starts empty at its new base
+        let config = build(20);
+        emu.load(emu::compile(source, &config));
+        emu.function(FUNC_INIT);
+        emu.function("main");
+        assert_eq!(emu.cell(&config.memory(), 0), 21);
+    }
 }
@@ -0,0 +1,164 @@
+use crate::pack::Output;
+
+use super::PREFIX;
+
+/// Branches of the search trees dispatching block ids and `switch` cases.
+pub const DISPATCH_FANOUT: usize = 2;
+
+/// Generate `cmd_name`, which runs the command produced by `generate` for
+/// the value of `pointer_reg`, or `fallback` if it is not below `size`.
+/// Values out of range do nothing without a `fallback`.
+/// Every test is additionally subject to `guard`, an `execute` subcommand,
+/// if given. Every function of the tree tests up to `fanout` ranges, 0
+/// tests every value in a single function.
+#[allow(clippy::too_many_arguments)] // most of them differ between the trees
+pub fn gen_search<F>(
+    output: &mut Output,
+    cmd_name: &str,
+    pointer_reg: &str,
+    guard: Option<&str>,
+    size: usize,
+    fanout: usize,
+    fallback: Option<&str>,
+    generate: F,
+) where
+    F: Fn(usize) -> String,
+{
+    let execute = match guard {
+        Some(guard) => format!("execute {guard} "),
+        None => "execute ".to_string(),
+    };
+
+    let generate = |nth| {
+        let s = generate(nth);
+        assert!(s.find("\n").is_none());
+        s
+    };
+
+    let entry = if size == 0 {
+        fallback.unwrap_or_default().to_string()
+    } else {
+        let entry_fn = if size == 1 {
+            generate(0)
+        } else {
+            let search = Search {
+                id: cmd_name,
+                pointer_reg,
+                execute: &execute,
+                tree: Tree::new(size, fanout),
+            };
+            search.generate(output, 0, size, search.tree.root, &generate);
+            format!("function {}", search.fn_name(0, size))
+        };
+
+        let upper_bound = size - 1;
+        let search = format!(
+            "{execute}if score {PREFIX} {pointer_reg} matches 0..{upper_bound} run {entry_fn}"
+        );
+        match fallback {
+            Some(fallback) => format!(
+                "{execute}unless score {PREFIX} {pointer_reg} matches 0..{upper_bound} run {fallback}\n\
+                {search}"
+            ),
+            None => search,
+        }
+    };
+
+    output.add_function(cmd_name, entry);
+}
+
+/// Commands run by a search over `size` values in the worst case, including
+/// the one produced by `generate`. An `execute` counts twice when it runs its
+/// command.
+pub fn search_cost(size: usize, fanout: usize) -> usize {
+    match size {
+        0 => 1,
+        1 => 3,
+        // two tests at the entry, and on every level of the tree a test for
+        // every range, one of them running the next level
+        _ => {
+            let tree = Tree::new(size, fanout);
+            let mut cost = 3 + size.div_ceil(tree.root) + 1;
+            let mut chunk = tree.root;
+            while chunk > 1 {
+                cost += tree.fanout + 1;
+                chunk /= tree.fanout;
+            }
+            cost
+        }
+    }
+}
+
+/// Shape of a search tree.
+struct Tree {
+    fanout: usize,
+    /// Values in each range tested by the root, a power of `fanout`.
+    root: usize,
+}
+
+impl Tree {
+    fn new(size: usize, fanout: usize) -> Self {
+        let fanout = match fanout {
+            0 => size,
+            _ => fanout,
+        };
+        let mut root = 1;
+        while root * fanout < size {
+            root *= fanout;
+        }
+        Self { fanout, root }
+    }
+}
+
+/// The functions of a search tree, all named after `id`.
+struct Search<'a> {
+    id: &'a str,
+    pointer_reg: &'a str,
+    execute: &'a str,
+    tree: Tree,
+}
+
+impl Search<'_> {
+    fn fn_name(&self, start: usize, end: usize) -> String {
+        format!("{}/Search_{start}_{}", self.id, end - 1)
+    }
+
+    /// Generate the function testing the values from `start` to `end` in
+    /// ranges of `chunk` values.
+    fn generate<F>(&self, output: &mut Output, start: usize, end: usize, chunk: usize, generate: &F)
+    where
+        F: Fn(usize) -> String,
+    {
+        let Search {
+            pointer_reg,
+            execute,
+            ..
+        } = self;
+
+        // the last range of a level may be too short to need all the levels
+        let mut chunk = chunk;
+        while chunk > 1 && chunk >= end - start {
+            chunk /= self.tree.fanout;
+        }
+
+        let mut content = Vec::new();
+        for low in (start..end).step_by(chunk) {
+            let high = (low + chunk).min(end);
+            content.push(if high - low == 1 {
+                format!(
+                    "{execute}if score {PREFIX} {pointer_reg} matches {low} run {}",
+                    generate(low)
+                )
+            } else {
+                self.generate(output, low, high, chunk / self.tree.fanout, generate);
+                format!(
+                    "{execute}if score {PREFIX} {pointer_reg} matches {low}..{} run function {}",
+                    high - 1,
+                    self.fn_name(low, high)
+                )
+            });
+        }
+
+        output.add_function(&self.fn_name(start, end), content.join("\n"));
+    }
+}
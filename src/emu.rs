@@ -0,0 +1,280 @@
+//! Runs the generated functions the way the game does, so the tests can
+//! check what a program computes.
+
+use std::collections::{HashMap, HashSet};
+
+use serde_json::Value;
+
+use crate::{
+    bootstrap::{
+        generate_module_memory, Memory, FUNC_INIT, FUNC_TICK, PREFIX, PROGRAM_COUNTER, TIMER,
+    },
+    manifest::BuildConfig,
+    mas::VirtualMachine,
+    pack::Output,
+};
+
+/// Commands a test may run before it is considered stuck.
+const COMMAND_LIMIT: usize = 5_000_000;
+
+/// Compile `source` like `mcvm build` does.
+pub fn compile(source: &str, config: &BuildConfig) -> Output {
+    let mut vm = VirtualMachine::new();
+    vm.parse_source(source).unwrap();
+    vm.pass_arguments().unwrap();
+    vm.optimize(config);
+
+    let mut output = Output::new();
+    generate_module_memory(
+        &mut output,
+        &config.memory(),
+        config.heap_size,
+        config.dump_size,
+        config.threads,
+        config.checks,
+    );
+    vm.generate(&mut output, config).unwrap();
+    output
+}
+
+/// Compile `source`, run `init` and the entry `main`, and let the program
+/// run for up to `ticks` more ticks until it stops.
+pub fn run(source: &str, config: &BuildConfig, ticks: usize) -> Emulator {
+    let mut emu = Emulator::new(compile(source, config));
+    emu.function(FUNC_INIT);
+    emu.function("main");
+    emu.run_ticks(ticks);
+    emu
+}
+
+pub struct Emulator {
+    output: Output,
+    objectives: HashSet<String>,
+    scores: HashMap<(String, String), i32>,
+    /// Messages of `say` and `tellraw`, in order.
+    pub said: Vec<String>,
+    pub commands: usize,
+}
+
+impl Emulator {
+    pub fn new(output: Output) -> Self {
+        Self {
+            output,
+            objectives: HashSet::new(),
+            scores: HashMap::new(),
+            said: Vec::new(),
+            commands: 0,
+        }
+    }
+
+    /// Replace the pack, as if the world loaded another build of it.
+    pub fn load(&mut self, output: Output) {
+        self.output = output;
+    }
+
+    /// Score of `player`, `None` if it has none or the objective is missing.
+    pub fn score(&self, player: &str, objective: &str) -> Option<i32> {
+        if !self.objectives.contains(objective) {
+            return None;
+        }
+        self.scores
+            .get(&(player.to_string(), objective.to_string()))
+            .copied()
+    }
+
+    /// Score of the `PREFIX` fake player.
+    pub fn reg(&self, objective: &str) -> i32 {
+        self.score(PREFIX, objective)
+            .unwrap_or_else(|| panic!("no score for {objective}"))
+    }
+
+    pub fn set(&mut self, player: &str, objective: &str, value: i32) {
+        assert!(
+            self.objectives.contains(objective),
+            "objective `{objective}` is not added"
+        );
+        self.scores
+            .insert((player.to_string(), objective.to_string()), value);
+    }
+
+    /// Value of the `nth` cell of `memory`, 0 if it has none.
+    pub fn cell(&self, memory: &Memory, nth: usize) -> i32 {
+        let cell = memory.layout.cell(nth);
+        let (player, objective) = cell.split_once(' ').unwrap();
+        self.score(player, objective).unwrap_or(0)
+    }
+
+    /// Run `FUNC_TICK` until the program stops or sleeps no more, at most
+    /// `ticks` times.
+    pub fn run_ticks(&mut self, ticks: usize) {
+        for _ in 0..ticks {
+            let running = self.score(PREFIX, PROGRAM_COUNTER).unwrap_or(0) != 0
+                || self.score(PREFIX, TIMER).unwrap_or(0) > 0;
+            if !running {
+                break;
+            }
+            self.function(FUNC_TICK);
+        }
+    }
+
+    pub fn function(&mut self, name: &str) {
+        let content = self
+            .output
+            .function(name)
+            .unwrap_or_else(|| panic!("function `{name}` is not generated"))
+            .to_string();
+        for line in content.lines() {
+            let line = line.trim();
+            if !line.is_empty() && !line.starts_with('#') {
+                self.command(line);
+            }
+        }
+    }
+
+    fn command(&mut self, command: &str) {
+        self.commands += 1;
+        assert!(self.commands < COMMAND_LIMIT, "the program does not stop");
+
+        let tokens: Vec<&str> = command.split_whitespace().collect();
+        match tokens[0] {
+            "function" => self.function(tokens[1]),
+            "say" => self.said.push(command["say ".len()..].to_string()),
+            "tellraw" => self.tellraw(command.splitn(3, ' ').nth(2).unwrap()),
+            "execute" => self.execute(&tokens[1..]),
+            "scoreboard" => self.scoreboard(&tokens[1..]),
+            _ => panic!("unknown command `{command}`"),
+        }
+    }
+
+    fn tellraw(&mut self, json: &str) {
+        let json: Value = serde_json::from_str(json).unwrap();
+        let mut message = String::new();
+        for part in json["rawtext"].as_array().unwrap() {
+            if let Some(text) = part["text"].as_str() {
+                message += text;
+            } else {
+                let score = &part["score"];
+                let value = self.score(
+                    score["name"].as_str().unwrap(),
+                    score["objective"].as_str().unwrap(),
+                );
+                message += &value.map_or(String::new(), |v| v.to_string());
+            }
+        }
+        self.said.push(message);
+    }
+
+    fn execute(&mut self, mut tokens: &[&str]) {
+        loop {
+            match tokens {
+                ["run", rest @ ..] => return self.command(&rest.join(" ")),
+                [test @ ("if" | "unless"), "score", player, objective, rest @ ..] => {
+                    let value = self.score(player, objective);
+                    let (passed, rest) = match rest {
+                        ["matches", range, rest @ ..] => {
+                            (value.is_some_and(|v| matches(v, range)), rest)
+                        }
+                        [op, other, other_objective, rest @ ..] => {
+                            let other = self.score(other, other_objective);
+                            let passed = match (value, other) {
+                                (Some(a), Some(b)) => match *op {
+                                    "=" => a == b,
+                                    "<" => a < b,
+                                    "<=" => a <= b,
+                                    ">" => a > b,
+                                    ">=" => a >= b,
+                                    _ => panic!("unknown comparison `{op}`"),
+                                },
+                                _ => false,
+                            };
+                            (passed, rest)
+                        }
+                        _ => panic!("invalid score test `{}`", tokens.join(" ")),
+                    };
+                    // a missing score fails `if` and passes `unless`
+                    if passed != (*test == "if") {
+                        return;
+                    }
+                    tokens = rest;
+                }
+                _ => panic!("unknown execute `{}`", tokens.join(" ")),
+            }
+        }
+    }
+
+    fn scoreboard(&mut self, tokens: &[&str]) {
+        match tokens {
+            ["objectives", "add", name, ..] => {
+                self.objectives.insert(name.to_string());
+            }
+            ["objectives", "remove", name] => {
+                self.objectives.remove(*name);
+                self.scores.retain(|(_, objective), _| objective != name);
+            }
+            ["players", "set", player, objective, value] => {
+                self.set(player, objective, value.parse().unwrap())
+            }
+            ["players", "add", player, objective, value] => {
+                let old = self.score(player, objective).unwrap_or(0);
+                self.set(player, objective, old.wrapping_add(value.parse().unwrap()))
+            }
+            ["players", "remove", player, objective, value] => {
+                let old = self.score(player, objective).unwrap_or(0);
+                self.set(player, objective, old.wrapping_sub(value.parse().unwrap()))
+            }
+            ["players", "random", player, objective, min, _] => {
+                self.set(player, objective, min.parse().unwrap())
+            }
+            ["players", "reset", player] => self.scores.retain(|(p, _), _| p != player),
+            ["players", "reset", player, objective] => {
+                self.scores
+                    .remove(&(player.to_string(), objective.to_string()));
+            }
+            ["players", "operation", player, objective, op, other, other_objective] => {
+                let b = self
+                    .score(other, other_objective)
+                    .unwrap_or_else(|| panic!("no score {other} {other_objective}"));
+                if *op == "=" {
+                    return self.set(player, objective, b);
+                }
+                let a = self
+                    .score(player, objective)
+                    .unwrap_or_else(|| panic!("no score {player} {objective}"));
+                let value = match *op {
+                    "><" => {
+                        self.set(other, other_objective, a);
+                        b
+                    }
+                    "+=" => a.wrapping_add(b),
+                    "-=" => a.wrapping_sub(b),
+                    "*=" => a.wrapping_mul(b),
+                    // dividing by 0 fails without changing the score
+                    "/=" | "%=" if b == 0 => a,
+                    // both round towards negative infinity
+                    "/=" => a.wrapping_div(b) - rounded_up(a, b) as i32,
+                    "%=" => a.wrapping_rem(b) + if rounded_up(a, b) { b } else { 0 },
+                    "<" => a.min(b),
+                    ">" => a.max(b),
+                    _ => panic!("unknown operation `{op}`"),
+                };
+                self.set(player, objective, value);
+            }
+            _ => panic!("unknown scoreboard command `{}`", tokens.join(" ")),
+        }
+    }
+}
+
+/// Whether `a / b` rounds up when truncated, because the signs differ.
+fn rounded_up(a: i32, b: i32) -> bool {
+    a.wrapping_rem(b) != 0 && (a < 0) != (b < 0)
+}
+
+fn matches(value: i32, range: &str) -> bool {
+    match range.split_once("..") {
+        Some((low, high)) => {
+            (low.is_empty() || value >= low.parse().unwrap())
+                && (high.is_empty() || value <= high.parse().unwrap())
+        }
+        None => value == range.parse::<i32>().unwrap(),
+    }
+}
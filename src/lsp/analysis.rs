@@ -0,0 +1,181 @@
+use crate::mas::{parse_line, FuncOrInst};
+
+/// A range inside one line, in bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Span {
+    pub line: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    fn of(line_index: usize, line: &str, part: &str) -> Self {
+        let start = part.as_ptr() as usize - line.as_ptr() as usize;
+        Span {
+            line: line_index,
+            start,
+            end: start + part.len(),
+        }
+    }
+
+    pub fn contains(&self, line: usize, col: usize) -> bool {
+        self.line == line && (self.start..=self.end).contains(&col)
+    }
+}
+
+pub struct LabelDef {
+    pub name: String,
+    pub args: Vec<String>,
+    pub span: Span,
+    /// Last line of the instructions under this label.
+    pub last_line: usize,
+}
+
+pub struct LabelRef {
+    pub name: String,
+    pub span: Span,
+}
+
+pub struct Problem {
+    pub span: Span,
+    pub message: String,
+}
+
+/// What the language server knows about one source file.
+#[derive(Default)]
+pub struct Analysis {
+    pub labels: Vec<LabelDef>,
+    pub references: Vec<LabelRef>,
+    pub problems: Vec<Problem>,
+}
+
+pub const MNEMONICS: &[&str] = &[
+    "cmd", "mov", "set", "load", "store", "loadp", "storep", "cmp", "cmpin", "b", "bi", "bn",
+    "calc", "rand", "switch", "yield", "sleep", "spawn", "join", "call", "push", "pop", "alloc",
+    "free", "bank", "addr", "icall", "ret", "debug", "log",
+];
+
+pub const REGISTERS: &[&str] = &["R0", "R1", "R2", "R3"];
+
+/// Mnemonics whose operand is a label.
+pub const BRANCHES: &[&str] = &["b", "bi", "bn", "spawn", "call"];
+
+pub fn analyze(text: &str) -> Analysis {
+    let mut analysis = Analysis::default();
+    let mut last_code_line = 0;
+
+    for (line_index, line) in text.lines().enumerate() {
+        let args: Vec<&str> = match analysis.labels.last() {
+            Some(def) => def.args.iter().map(String::as_str).collect(),
+            None => Vec::new(),
+        };
+        let loi = match parse_line(line, line_index + 1, &args) {
+            Ok(Some(loi)) => loi,
+            Ok(None) => continue,
+            Err(_) => {
+                analysis.problems.push(Problem {
+                    span: Span::of(line_index, line, line.trim_end()),
+                    message: parse_error_message(line),
+                });
+                continue;
+            }
+        };
+
+        match loi {
+            FuncOrInst::Function(func) => {
+                if let Some(prev) = analysis.labels.last_mut() {
+                    prev.last_line = last_code_line;
+                }
+
+                let span = Span::of(line_index, line, func.name);
+                if analysis.labels.iter().any(|def| def.name == func.name) {
+                    analysis.problems.push(Problem {
+                        span,
+                        message: format!("duplicated function `{}`", func.name),
+                    });
+                }
+
+                analysis.labels.push(LabelDef {
+                    name: func.name.to_string(),
+                    args: func.args.iter().map(|arg| arg.to_string()).collect(),
+                    span,
+                    last_line: line_index,
+                });
+            }
+
+            FuncOrInst::Instruction(inst) => {
+                if analysis.labels.is_empty() {
+                    analysis.problems.push(Problem {
+                        span: Span::of(line_index, line, line.trim()),
+                        message: "instructions must under a label, \
+                            you need to define a label first"
+                            .to_string(),
+                    });
+                }
+
+                for label in inst.labels() {
+                    analysis.references.push(LabelRef {
+                        name: label.to_string(),
+                        span: Span::of(line_index, line, label),
+                    });
+                }
+            }
+        }
+
+        last_code_line = line_index;
+    }
+
+    if let Some(prev) = analysis.labels.last_mut() {
+        prev.last_line = last_code_line;
+    }
+
+    analysis
+}
+
+fn parse_error_message(line: &str) -> String {
+    let mnemonic = line.split_whitespace().next().unwrap_or_default();
+    if MNEMONICS.contains(&mnemonic) {
+        format!("invalid operands for `{mnemonic}`")
+    } else if mnemonic.ends_with(':') || line.contains(':') {
+        "invalid label definition".to_string()
+    } else {
+        format!("unknown instruction `{mnemonic}`")
+    }
+}
+
+/// Index of the whitespace separated word under `col` in `line`, with the
+/// word itself. Trailing comments are not considered words.
+pub fn word_at(line: &str, col: usize) -> Option<(usize, &str)> {
+    let code = line.split('#').next().unwrap_or_default();
+    let mut index = 0;
+    let mut rest = code;
+
+    loop {
+        let trimmed = rest.trim_start();
+        if trimmed.is_empty() {
+            return None;
+        }
+        let start = code.len() - trimmed.len();
+        let len = trimmed
+            .find(|c: char| c.is_whitespace())
+            .unwrap_or(trimmed.len());
+
+        if (start..=start + len).contains(&col) {
+            let word = &trimmed[..len];
+            return Some((index, word.trim_end_matches(':')));
+        }
+
+        rest = &trimmed[len..];
+        index += 1;
+    }
+}
+
+/// Documentation of each instruction, taken from `InstructionGuide.md`.
+pub fn instruction_doc(mnemonic: &str) -> Option<&'static str> {
+    const GUIDE: &str = include_str!("../../InstructionGuide.md");
+
+    GUIDE.split("\n## ").skip(1).find_map(|section| {
+        let (title, _) = section.split_once('\n')?;
+        (title.trim() == mnemonic).then(|| section.trim_end())
+    })
+}
@@ -0,0 +1,470 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::Path,
+};
+
+use anyhow::{anyhow, Result};
+use lsp_server::{Connection, Message, Notification, Request, RequestId, Response};
+use lsp_types::{
+    notification::{
+        DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument,
+        Notification as NotificationTrait, PublishDiagnostics,
+    },
+    request::{
+        Completion, DocumentSymbolRequest, GotoDefinition, HoverRequest, References,
+        Request as RequestTrait,
+    },
+    CompletionItem, CompletionItemKind, CompletionOptions, CompletionParams, CompletionResponse,
+    Diagnostic, DiagnosticSeverity, DocumentSymbol, DocumentSymbolParams,
+    DocumentSymbolResponse, GotoDefinitionParams, GotoDefinitionResponse, Hover, HoverContents,
+    HoverParams, HoverProviderCapability, InitializeParams, Location, MarkupContent, MarkupKind,
+    OneOf, Position, PublishDiagnosticsParams, Range, ReferenceParams, ServerCapabilities,
+    SymbolKind, TextDocumentSyncCapability, TextDocumentSyncKind, Url,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::manifest::{Manifest, MANIFEST_NAME};
+
+use self::analysis::{analyze, instruction_doc, word_at, Analysis, Span};
+
+mod analysis;
+
+/// Run the language server on stdin and stdout until the client exits.
+pub fn run() -> Result<()> {
+    let (connection, io_threads) = Connection::stdio();
+
+    let capabilities = ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        definition_provider: Some(OneOf::Left(true)),
+        references_provider: Some(OneOf::Left(true)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        completion_provider: Some(CompletionOptions::default()),
+        document_symbol_provider: Some(OneOf::Left(true)),
+        ..Default::default()
+    };
+    let params = connection.initialize(serde_json::to_value(capabilities)?)?;
+    let params: InitializeParams = serde_json::from_value(params)?;
+
+    let mut server = Server::default();
+    #[allow(deprecated)] // `workspace_folders` is not sent by every client
+    if let Some(root) = params.root_uri.and_then(|uri| uri.to_file_path().ok()) {
+        server.load_workspace(&root);
+    }
+
+    server.main_loop(&connection)?;
+    // the writer thread only stops once the connection is gone
+    drop(connection);
+    io_threads.join()?;
+    Ok(())
+}
+
+struct File {
+    text: String,
+    analysis: Analysis,
+    /// Opened in the editor, otherwise read from the disk.
+    open: bool,
+}
+
+impl File {
+    fn new(text: String, open: bool) -> Self {
+        Self {
+            analysis: analyze(&text),
+            text,
+            open,
+        }
+    }
+
+    fn line(&self, line: usize) -> &str {
+        self.text.lines().nth(line).unwrap_or_default()
+    }
+
+    fn range(&self, span: Span) -> Range {
+        let line = self.line(span.line);
+        Range::new(
+            position(line, span.line, span.start),
+            position(line, span.line, span.end),
+        )
+    }
+}
+
+fn send_diagnostics(connection: &Connection, uri: Url, diagnostics: Vec<Diagnostic>) -> Result<()> {
+    let params = PublishDiagnosticsParams::new(uri, diagnostics, None);
+    connection
+        .sender
+        .send(Message::Notification(Notification::new(
+            PublishDiagnostics::METHOD.to_string(),
+            params,
+        )))?;
+    Ok(())
+}
+
+fn position(line: &str, line_index: usize, byte: usize) -> Position {
+    Position::new(line_index as u32, line[..byte].encode_utf16().count() as u32)
+}
+
+fn byte_offset(line: &str, character: u32) -> usize {
+    let mut units = 0;
+    for (offset, c) in line.char_indices() {
+        if units >= character as usize {
+            return offset;
+        }
+        units += c.len_utf16();
+    }
+    line.len()
+}
+
+#[derive(Default)]
+struct Server {
+    files: BTreeMap<Url, File>,
+    /// Source files declared by the workspace manifest.
+    sources: BTreeSet<Url>,
+    /// Documents closed since the diagnostics were last published, whose
+    /// diagnostics must be cleared.
+    closed: BTreeSet<Url>,
+}
+
+impl Server {
+    /// Index the sources declared by the workspace manifest, so labels
+    /// defined in files that are not open are known.
+    fn load_workspace(&mut self, root: &Path) {
+        let Ok((manifest, root)) = Manifest::load(&root.join(MANIFEST_NAME)) else {
+            return;
+        };
+
+        for path in &manifest.build.sources {
+            let path = root.join(path);
+            let (Ok(text), Ok(uri)) = (fs::read_to_string(&path), Url::from_file_path(&path))
+            else {
+                continue;
+            };
+            self.sources.insert(uri.clone());
+            self.files.insert(uri, File::new(text, false));
+        }
+    }
+
+    fn main_loop(&mut self, connection: &Connection) -> Result<()> {
+        for msg in &connection.receiver {
+            match msg {
+                Message::Request(req) => {
+                    if connection.handle_shutdown(&req)? {
+                        return Ok(());
+                    }
+                    let response = self.handle_request(req);
+                    connection.sender.send(Message::Response(response))?;
+                }
+                Message::Notification(not) => {
+                    if self.handle_notification(not)? {
+                        self.publish_diagnostics(connection)?;
+                    }
+                }
+                Message::Response(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_request(&self, req: Request) -> Response {
+        let id = req.id.clone();
+        let result = match req.method.as_str() {
+            GotoDefinition::METHOD => {
+                respond::<GotoDefinition, _>(req, |params| self.definition(params))
+            }
+            References::METHOD => respond::<References, _>(req, |params| self.references(params)),
+            HoverRequest::METHOD => respond::<HoverRequest, _>(req, |params| self.hover(params)),
+            Completion::METHOD => respond::<Completion, _>(req, |params| self.completion(params)),
+            DocumentSymbolRequest::METHOD => {
+                respond::<DocumentSymbolRequest, _>(req, |params| self.symbols(params))
+            }
+            method => Err(anyhow!("unsupported request `{method}`")),
+        };
+
+        match result {
+            Ok(value) => Response::new_ok(id, value),
+            Err(e) => Response::new_err(
+                id,
+                lsp_server::ErrorCode::InvalidRequest as i32,
+                e.to_string(),
+            ),
+        }
+    }
+
+    /// Returns whether the documents changed.
+    fn handle_notification(&mut self, not: Notification) -> Result<bool> {
+        match not.method.as_str() {
+            DidOpenTextDocument::METHOD => {
+                let params = parse_params::<DidOpenTextDocument>(not.params)?;
+                let doc = params.text_document;
+                self.files.insert(doc.uri, File::new(doc.text, true));
+            }
+            DidChangeTextDocument::METHOD => {
+                let params = parse_params::<DidChangeTextDocument>(not.params)?;
+                // full synchronization, the last change is the whole text
+                if let Some(change) = params.content_changes.into_iter().last() {
+                    self.files.insert(
+                        params.text_document.uri,
+                        File::new(change.text, true),
+                    );
+                }
+            }
+            DidCloseTextDocument::METHOD => {
+                let params = parse_params::<DidCloseTextDocument>(not.params)?;
+                let uri = params.text_document.uri;
+                // workspace sources fall back to their content on the disk
+                let on_disk = uri
+                    .to_file_path()
+                    .ok()
+                    .filter(|_| self.sources.contains(&uri))
+                    .and_then(|path| fs::read_to_string(path).ok());
+                match on_disk {
+                    Some(text) => self.files.insert(uri.clone(), File::new(text, false)),
+                    None => self.files.remove(&uri),
+                };
+                self.closed.insert(uri);
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    fn publish_diagnostics(&mut self, connection: &Connection) -> Result<()> {
+        // the client keeps showing the last diagnostics of a closed document
+        for uri in std::mem::take(&mut self.closed) {
+            send_diagnostics(connection, uri, Vec::new())?;
+        }
+
+        for (uri, file) in self.files.iter().filter(|(_, f)| f.open) {
+            let mut diagnostics: Vec<Diagnostic> = file
+                .analysis
+                .problems
+                .iter()
+                .map(|problem| error(file.range(problem.span), problem.message.clone()))
+                .collect();
+
+            for reference in &file.analysis.references {
+                if self.definition_of(&reference.name).is_none() {
+                    diagnostics.push(error(
+                        file.range(reference.span),
+                        format!("label `{}` is not defined", reference.name),
+                    ));
+                }
+            }
+
+            for label in &file.analysis.labels {
+                let elsewhere = self
+                    .files
+                    .iter()
+                    .filter(|(other, _)| *other != uri)
+                    .any(|(_, f)| f.analysis.labels.iter().any(|l| l.name == label.name));
+                if elsewhere {
+                    diagnostics.push(error(
+                        file.range(label.span),
+                        format!("label `{}` is also defined in another file", label.name),
+                    ));
+                }
+            }
+
+            send_diagnostics(connection, uri.clone(), diagnostics)?;
+        }
+        Ok(())
+    }
+
+    fn definition_of(&self, name: &str) -> Option<Location> {
+        self.files.iter().find_map(|(uri, file)| {
+            let label = file.analysis.labels.iter().find(|l| l.name == name)?;
+            Some(Location::new(uri.clone(), file.range(label.span)))
+        })
+    }
+
+    /// The label defined or referenced at the given position.
+    fn label_at(&self, uri: &Url, pos: Position) -> Option<&str> {
+        let file = self.files.get(uri)?;
+        let line = pos.line as usize;
+        let col = byte_offset(file.line(line), pos.character);
+
+        let analysis = &file.analysis;
+        analysis
+            .labels
+            .iter()
+            .map(|l| (&l.name, l.span))
+            .chain(analysis.references.iter().map(|r| (&r.name, r.span)))
+            .find(|(_, span)| span.contains(line, col))
+            .map(|(name, _)| name.as_str())
+    }
+
+    fn definition(&self, params: GotoDefinitionParams) -> Option<GotoDefinitionResponse> {
+        let pos = params.text_document_position_params;
+        let name = self.label_at(&pos.text_document.uri, pos.position)?;
+        self.definition_of(name).map(GotoDefinitionResponse::Scalar)
+    }
+
+    fn references(&self, params: ReferenceParams) -> Option<Vec<Location>> {
+        let pos = params.text_document_position;
+        let name = self.label_at(&pos.text_document.uri, pos.position)?;
+
+        let mut locations = Vec::new();
+        if params.context.include_declaration {
+            locations.extend(self.definition_of(name));
+        }
+        for (uri, file) in &self.files {
+            for reference in file.analysis.references.iter().filter(|r| r.name == name) {
+                locations.push(Location::new(uri.clone(), file.range(reference.span)));
+            }
+        }
+        Some(locations)
+    }
+
+    fn hover(&self, params: HoverParams) -> Option<Hover> {
+        let pos = params.text_document_position_params;
+        let uri = &pos.text_document.uri;
+
+        let value = if let Some(name) = self.label_at(uri, pos.position) {
+            let (_, file) = self
+                .files
+                .iter()
+                .find(|(_, f)| f.analysis.labels.iter().any(|l| l.name == name))?;
+            let label = file.analysis.labels.iter().find(|l| l.name == name)?;
+            format!("```\n{}({}):\n```", label.name, label.args.join(", "))
+        } else {
+            let file = self.files.get(uri)?;
+            let line = file.line(pos.position.line as usize);
+            let (index, word) = word_at(line, byte_offset(line, pos.position.character))?;
+            if index != 0 {
+                return None;
+            }
+            instruction_doc(word)?.to_string()
+        };
+
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value,
+            }),
+            range: None,
+        })
+    }
+
+    fn completion(&self, params: CompletionParams) -> Option<CompletionResponse> {
+        let pos = params.text_document_position;
+        let file = self.files.get(&pos.text_document.uri)?;
+        let line = file.line(pos.position.line as usize);
+        let col = byte_offset(line, pos.position.character);
+
+        let code = &line[..col];
+        let words: Vec<&str> = code.split_whitespace().collect();
+        // the word being typed is not complete yet
+        let index = if code.ends_with(char::is_whitespace) {
+            words.len()
+        } else {
+            words.len().saturating_sub(1)
+        };
+
+        let items: Vec<CompletionItem> = if index == 0 {
+            analysis::MNEMONICS
+                .iter()
+                .map(|m| CompletionItem {
+                    label: m.to_string(),
+                    kind: Some(CompletionItemKind::KEYWORD),
+                    ..Default::default()
+                })
+                .collect()
+        } else if analysis::BRANCHES.contains(&words[0])
+            || matches!(words[0], "switch" | "addr") && index >= 2
+        {
+            self.files
+                .values()
+                .flat_map(|f| &f.analysis.labels)
+                .map(|l| CompletionItem {
+                    label: l.name.clone(),
+                    kind: Some(CompletionItemKind::FUNCTION),
+                    ..Default::default()
+                })
+                .collect()
+        } else if matches!(words[0], "load" | "store") {
+            // the parameters of the label the line is under
+            let line_index = pos.position.line as usize;
+            file.analysis
+                .labels
+                .iter()
+                .rev()
+                .find(|l| l.span.line < line_index)
+                .into_iter()
+                .flat_map(|l| &l.args)
+                .map(|arg| CompletionItem {
+                    label: arg.clone(),
+                    kind: Some(CompletionItemKind::VARIABLE),
+                    ..Default::default()
+                })
+                .collect()
+        } else {
+            analysis::REGISTERS
+                .iter()
+                .map(|r| CompletionItem {
+                    label: r.to_string(),
+                    kind: Some(CompletionItemKind::VARIABLE),
+                    ..Default::default()
+                })
+                .collect()
+        };
+
+        Some(CompletionResponse::Array(items))
+    }
+
+    fn symbols(&self, params: DocumentSymbolParams) -> Option<DocumentSymbolResponse> {
+        let file = self.files.get(&params.text_document.uri)?;
+
+        let symbols = file
+            .analysis
+            .labels
+            .iter()
+            .map(|label| {
+                let last = file.line(label.last_line);
+                #[allow(deprecated)] // `deprecated` field must still be given
+                DocumentSymbol {
+                    name: label.name.clone(),
+                    detail: Some(format!("({})", label.args.join(", "))),
+                    kind: SymbolKind::FUNCTION,
+                    tags: None,
+                    deprecated: None,
+                    range: Range::new(
+                        Position::new(label.span.line as u32, 0),
+                        position(last, label.last_line, last.len()),
+                    ),
+                    selection_range: file.range(label.span),
+                    children: None,
+                }
+            })
+            .collect();
+
+        Some(DocumentSymbolResponse::Nested(symbols))
+    }
+}
+
+fn error(range: Range, message: String) -> Diagnostic {
+    Diagnostic {
+        range,
+        severity: Some(DiagnosticSeverity::ERROR),
+        source: Some("mcvm".to_string()),
+        message,
+        ..Default::default()
+    }
+}
+
+fn parse_params<N>(params: serde_json::Value) -> Result<N::Params>
+where
+    N: NotificationTrait,
+    N::Params: DeserializeOwned,
+{
+    Ok(serde_json::from_value(params)?)
+}
+
+fn respond<R, F>(req: Request, handler: F) -> Result<serde_json::Value>
+where
+    R: RequestTrait,
+    R::Params: DeserializeOwned,
+    R::Result: Serialize,
+    F: FnOnce(R::Params) -> R::Result,
+{
+    let (_, params): (RequestId, R::Params) = req.extract(R::METHOD)?;
+    Ok(serde_json::to_value(handler(params))?)
+}
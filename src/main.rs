@@ -1,36 +1,295 @@
-use std::{env, fs, path::PathBuf};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, SystemTime},
+};
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use bootstrap::generate_module_memory;
-use mas::VirtualMachine;
+use manifest::{BuildConfig, Manifest};
+use mas::{format_source, VirtualMachine};
+use pack::Output;
 
 mod bootstrap;
+#[cfg(test)]
+mod emu;
+mod lsp;
+mod manifest;
 mod mas;
+mod pack;
 mod parse;
 
+const USAGE: &str = "\
+usage:
+    mcvm build [--watch] [options] [project]
+                                build the project described by `mcvm.toml`,
+                                and rebuild whenever a source file changes
+                                if `--watch` is given
+    mcvm fmt [--check] [files]  format Mas sources, the sources of `mcvm.toml`
+                                if no file is given; only report unformatted
+                                files if `--check` is given
+    mcvm lsp                    run the language server on stdio
+    mcvm <input> <pack> [options]
+                                compile a single file into a behavior pack
+
+options override the settings of `mcvm.toml`:
+    -O0, -O1, -O2               optimization level, defaults to 1
+    --release                   leave out the run time checks";
+
 fn main() -> Result<()> {
-    let mut args = env::args();
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    match args.first().map(String::as_str) {
+        Some("build") => {
+            let mut watch_mode = false;
+            let mut project = Path::new(".");
+            let mut flags = Vec::new();
+            for arg in &args[1..] {
+                match arg.as_str() {
+                    "--watch" => watch_mode = true,
+                    flag if flag.starts_with('-') => {
+                        if !BuildConfig::default().apply_flag(flag)? {
+                            return Err(anyhow!("unknown flag `{flag}`\n{USAGE}"));
+                        }
+                        flags.push(flag);
+                    }
+                    path => project = Path::new(path),
+                }
+            }
 
-    let mas_path = args
-        .next()
-        .ok_or_else(|| anyhow!("input file must be provided"))?;
+            if watch_mode {
+                watch(project, &flags)
+            } else {
+                build(project, &flags)
+            }
+        }
+        Some("fmt") => {
+            let check = args[1..].iter().any(|arg| arg == "--check");
+            let files: Vec<PathBuf> = args[1..]
+                .iter()
+                .filter(|arg| *arg != "--check")
+                .map(PathBuf::from)
+                .collect();
+            fmt(files, check)
+        }
+        Some("lsp") => lsp::run(),
+        Some(mas_path) => {
+            let pack_root = args
+                .get(1)
+                .ok_or_else(|| anyhow!("behavior pack path must be provided\n{USAGE}"))?;
 
-    let mut function_dir = PathBuf::from(
-        args.nth(1)
-            .ok_or_else(|| anyhow!("behavior pack path must be provided"))?,
+            let mut config = BuildConfig {
+                sources: vec![PathBuf::from(mas_path)],
+                ..Default::default()
+            };
+            if let Ok(s) = env::var("MCVM_MEM_SIZE") {
+                config.memory_size = s.parse()?;
+            }
+            if let Ok(s) = env::var("MCVM_MEM_LAYOUT") {
+                config.memory_layout = s.parse()?;
+            }
+            if let Ok(s) = env::var("MCVM_SEARCH_FANOUT") {
+                config.search_fanout = s.parse()?;
+            }
+            if let Ok(s) = env::var("MCVM_BANK_SIZE") {
+                config.bank_size = s.parse()?;
+            }
+            if let Ok(s) = env::var("MCVM_CURRENT_BANK") {
+                config.current_bank = s.parse()?;
+            }
+            if let Ok(s) = env::var("MCVM_HEAP_SIZE") {
+                config.heap_size = s.parse()?;
+            }
+            if let Ok(s) = env::var("MCVM_DUMP_SIZE") {
+                config.dump_size = s.parse()?;
+            }
+            if let Ok(s) = env::var("MCVM_ENTRIES") {
+                config.entries = s
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|entry| !entry.is_empty())
+                    .map(String::from)
+                    .collect();
+            }
+            config.check()?;
+            for flag in &args[2..] {
+                if !config.apply_flag(flag)? {
+                    return Err(anyhow!("unknown flag `{flag}`\n{USAGE}"));
+                }
+            }
+
+            let pack_root = Path::new(pack_root);
+            compile(Path::new("."), &config)?.sync(pack_root)?;
+            Ok(())
+        }
+        None => Err(anyhow!("input file must be provided\n{USAGE}")),
+    }
+}
+
+fn build(project: &Path, flags: &[&str]) -> Result<()> {
+    let (mut manifest, root) = Manifest::load(project)?;
+    for flag in flags {
+        manifest.build.apply_flag(flag)?;
+    }
+
+    let mut output = compile(&root, &manifest.build)?;
+    pack::add_manifest(&mut output, &manifest.package)?;
+    let stats = output.sync(&root.join(&manifest.build.output))?;
+    println!(
+        "built `{}`: {} files written, {} files removed",
+        manifest.package.name, stats.written, stats.removed
     );
-    function_dir.push("functions");
+    Ok(())
+}
+
+fn watch(project: &Path, flags: &[&str]) -> Result<()> {
+    let mut last_seen = None;
 
-    if function_dir.exists() {
-        fs::remove_dir_all(&function_dir)?;
+    loop {
+        let seen = modified_times(project);
+        if last_seen.as_ref() != Some(&seen) {
+            if let Err(e) = build(project, flags) {
+                eprintln!("error: {e:?}");
+            }
+            // files may change during the build
+            last_seen = Some(modified_times(project));
+        }
+
+        thread::sleep(Duration::from_millis(500));
+    }
+}
+
+/// The manifest and every source file it declares, with their modification
+/// times.
+fn modified_times(project: &Path) -> Vec<(PathBuf, Option<SystemTime>)> {
+    let mut files = vec![if project.is_dir() {
+        project.join(manifest::MANIFEST_NAME)
+    } else {
+        project.to_path_buf()
+    }];
+
+    // a broken manifest is reported by the build
+    if let Ok((manifest, root)) = Manifest::load(project) {
+        files.extend(manifest.build.sources.iter().map(|path| root.join(path)));
     }
-    fs::create_dir(&function_dir)?;
 
-    let size: usize = match env::var("MCVM_MEM_SIZE") {
-        Ok(s) => s.parse()?,
-        Err(_) => 128,
-    };
+    files
+        .into_iter()
+        .map(|path| {
+            let time = fs::metadata(&path).and_then(|m| m.modified()).ok();
+            (path, time)
+        })
+        .collect()
+}
+
+fn fmt(mut files: Vec<PathBuf>, check: bool) -> Result<()> {
+    if files.is_empty() {
+        let (manifest, root) = Manifest::load(Path::new("."))?;
+        files = manifest
+            .build
+            .sources
+            .iter()
+            .map(|path| root.join(path))
+            .collect();
+    }
 
-    generate_module_memory(&function_dir, size)?;
-    VirtualMachine::parse(&fs::read_to_string(&mas_path)?)?.generate(&function_dir)
+    let mut unformatted = 0;
+    for path in &files {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("cannot read source `{}`", path.display()))?;
+        let formatted =
+            format_source(&text).with_context(|| format!("in `{}`", path.display()))?;
+
+        if formatted == text {
+            continue;
+        }
+        if check {
+            println!("`{}` is not formatted", path.display());
+            unformatted += 1;
+        } else {
+            fs::write(path, formatted)?;
+            println!("formatted `{}`", path.display());
+        }
+    }
+
+    if unformatted > 0 {
+        return Err(anyhow!("{unformatted} files are not formatted"));
+    }
+    Ok(())
+}
+
+/// Compile the sources of `config` into the files of a behavior pack.
+/// Source paths are relative to `root`.
+fn compile(root: &Path, config: &BuildConfig) -> Result<Output> {
+    let sources = config
+        .sources
+        .iter()
+        .map(|path| {
+            let path = root.join(path);
+            fs::read_to_string(&path)
+                .with_context(|| format!("cannot read source `{}`", path.display()))
+                .map(|text| (path, text))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut vm = VirtualMachine::new();
+    for (path, text) in &sources {
+        vm.parse_source(text)
+            .with_context(|| format!("in `{}`", path.display()))?;
+    }
+
+    vm.pass_arguments()?;
+    vm.optimize(config);
+
+    let mut output = Output::new();
+    generate_module_memory(
+        &mut output,
+        &config.memory(),
+        config.heap_size,
+        config.dump_size,
+        config.threads,
+        config.checks,
+    );
+    let report = vm.generate(&mut output, config)?;
+    for label in &report.removed.labels {
+        println!("removed unreachable label `{label}`");
+    }
+    if report.removed.blocks > 0 {
+        println!("removed {} unreachable blocks", report.removed.blocks);
+    }
+    for warning in &report.warnings {
+        eprintln!("warning: {warning}");
+    }
+    let memory = config.memory();
+    println!(
+        "memory accesses that search for their cell run up to {} commands",
+        memory.search_cost()
+    );
+    if memory.current_bank {
+        println!(
+            "accesses to the current bank run up to {} commands",
+            memory.window_cost()
+        );
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use super::fmt;
+
+    #[test]
+    fn fmt_check_accepts_canonical_source() {
+        let path = env::temp_dir().join(format!("mcvm_fmt_check_{}.mas", process::id()));
+        fs::write(&path, "a:\nset        R0 1\nret\n\n# c\nb:\nret\n").unwrap();
+        let result = fmt(vec![path.clone()], true);
+        let after = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        result.unwrap();
+        assert_eq!(after, "a:\nset        R0 1\nret\n\n# c\nb:\nret\n");
+    }
 }
@@ -0,0 +1,246 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+
+use crate::{
+    bootstrap::{Memory, MemoryLayout},
+    mas::Register,
+};
+
+pub const MANIFEST_NAME: &str = "mcvm.toml";
+
+/// Content of `mcvm.toml`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    pub package: PackageInfo,
+    #[serde(default)]
+    pub build: BuildConfig,
+}
+
+/// Metadata written to the `manifest.json` of the behavior pack.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PackageInfo {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default = "default_version")]
+    pub version: [u32; 3],
+    #[serde(default = "default_engine_version")]
+    pub min_engine_version: [u32; 3],
+    /// Header uuid, derived from the package name if absent.
+    pub uuid: Option<String>,
+    /// Data module uuid, derived from the package name if absent.
+    pub module_uuid: Option<String>,
+}
+
+/// Settings that control code generation.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct BuildConfig {
+    /// Mas source files, relative to the manifest.
+    pub sources: Vec<PathBuf>,
+    /// Labels that keep their name and can be invoked with `/function`.
+    pub entries: Vec<String>,
+    pub memory_size: usize,
+    /// How the memory cells are stored, `objectives` or `players`.
+    pub memory_layout: MemoryLayout,
+    /// Ranges tested by every function of the memory search trees, 0 tests
+    /// every cell in a single function.
+    pub search_fanout: usize,
+    /// Cells of every bank the memory is split into, 0 for a memory without
+    /// banks.
+    pub bank_size: usize,
+    /// Whether `bank` can make a bank the current one, whose cells are
+    /// accessed without searching for the bank.
+    pub current_bank: bool,
+    /// Folder under `functions` that the entry labels are placed in.
+    pub namespace: Option<String>,
+    /// Root folder of the generated behavior pack, relative to the manifest.
+    pub output: PathBuf,
+    /// 0 keeps the code as written, 1 removes redundant instructions, 2
+    /// also folds constants and removes unused results.
+    pub opt_level: u8,
+    /// At level 2, labels with at most this many instructions are inlined
+    /// into every `b` and `call`.
+    pub inline_size: usize,
+    /// At level 2, labels referenced only once are inlined regardless of
+    /// their size.
+    pub inline_single_caller: bool,
+    /// At level 2, `call` right before `ret` reuses the current frame.
+    pub tail_calls: bool,
+    /// Commands the program may run before it yields until the next tick,
+    /// to stay below the `maxCommandChainLength` gamerule. Unlimited if
+    /// absent.
+    pub command_budget: Option<usize>,
+    /// Threads that can run at once, including the one running the entry
+    /// labels. Each gets an equal part of the memory before the heap for
+    /// its frames.
+    pub threads: usize,
+    /// Registers that `call label` pushes onto the stack before the call,
+    /// and pops once it returns.
+    pub saved_registers: Vec<Register>,
+    /// Whether memory addresses, frames and resumed block ids are checked
+    /// at run time, raising a trap when they are out of range.
+    pub checks: bool,
+    /// Cells at the end of the memory that `alloc` hands out, 0 for no
+    /// heap.
+    pub heap_size: usize,
+    /// Cells printed by `MCVM_Memory_Dump` and `debug dump`.
+    pub dump_size: usize,
+}
+
+impl Default for BuildConfig {
+    fn default() -> Self {
+        Self {
+            sources: Vec::new(),
+            entries: vec!["main".to_string()],
+            memory_size: 128,
+            memory_layout: MemoryLayout::Objectives,
+            search_fanout: 2,
+            bank_size: 0,
+            current_bank: false,
+            namespace: None,
+            output: PathBuf::from("build"),
+            opt_level: 1,
+            inline_size: 8,
+            inline_single_caller: true,
+            tail_calls: true,
+            command_budget: None,
+            threads: 1,
+            saved_registers: Vec::new(),
+            checks: true,
+            heap_size: 0,
+            dump_size: 16,
+        }
+    }
+}
+
+fn default_version() -> [u32; 3] {
+    [1, 0, 0]
+}
+
+fn default_engine_version() -> [u32; 3] {
+    [1, 20, 0]
+}
+
+impl Manifest {
+    /// Load the manifest from `path`, which is either the manifest itself or
+    /// the project folder containing it. Returns the manifest together with
+    /// the project folder.
+    pub fn load(path: &Path) -> Result<(Self, PathBuf)> {
+        let (file, root) = if path.is_dir() {
+            (path.join(MANIFEST_NAME), path.to_path_buf())
+        } else {
+            let root = match path.parent() {
+                Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+                _ => PathBuf::from("."),
+            };
+            (path.to_path_buf(), root)
+        };
+
+        let text = fs::read_to_string(&file)
+            .with_context(|| format!("cannot read manifest `{}`", file.display()))?;
+        let manifest: Manifest = toml::from_str(&text)
+            .with_context(|| format!("invalid manifest `{}`", file.display()))?;
+
+        if manifest.build.sources.is_empty() {
+            return Err(anyhow!("no source files are declared in `build.sources`"));
+        }
+
+        manifest.build.check()?;
+
+        Ok((manifest, root))
+    }
+}
+
+impl BuildConfig {
+    /// Apply a command line flag such as `-O2`, returns whether the flag is
+    /// a build setting.
+    pub fn apply_flag(&mut self, flag: &str) -> Result<bool> {
+        if flag == "--release" {
+            self.checks = false;
+            return Ok(true);
+        }
+
+        match flag.strip_prefix("-O") {
+            Some(level) => {
+                self.opt_level = level
+                    .parse()
+                    .map_err(|_| anyhow!("invalid optimization level `{flag}`"))?;
+                self.check()?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Memory before the heap, shared by the threads for their frames.
+    pub fn stack_size(&self) -> usize {
+        self.memory_size.saturating_sub(self.heap_size)
+    }
+
+    pub fn memory(&self) -> Memory {
+        Memory {
+            layout: self.memory_layout,
+            size: self.memory_size,
+            fanout: self.search_fanout,
+            bank_size: self.bank_size,
+            current_bank: self.current_bank,
+        }
+    }
+
+    /// Check that the settings are valid.
+    pub fn check(&self) -> Result<()> {
+        if self.opt_level > 2 {
+            return Err(anyhow!(
+                "optimization level must be 0, 1 or 2, found {}",
+                self.opt_level
+            ));
+        }
+
+        if self.search_fanout == 1 {
+            return Err(anyhow!("search fanout must be 0 or at least 2"));
+        }
+
+        if self.bank_size > self.memory_size {
+            return Err(anyhow!(
+                "bank size must be at most the memory size {}, found {}",
+                self.memory_size,
+                self.bank_size
+            ));
+        }
+
+        if self.current_bank && self.bank_size == 0 {
+            return Err(anyhow!("a current bank needs banks, set `bank_size`"));
+        }
+
+        if self.threads == 0 {
+            return Err(anyhow!("there must be at least one thread"));
+        }
+
+        // a block takes at least one cell and the one holding its size
+        if self.heap_size == 1 || self.heap_size > self.memory_size {
+            return Err(anyhow!(
+                "heap size must be 0 or between 2 and the memory size {}, found {}",
+                self.memory_size,
+                self.heap_size
+            ));
+        }
+
+        if let Some(ns) = &self.namespace {
+            if ns.is_empty() || !ns.chars().all(|c| c.is_alphanumeric() || c == '_') {
+                return Err(anyhow!(
+                    "namespace `{ns}` must only contain letters, digits and underscores"
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+
+use super::{Instruction, Register, VirtualMachine};
+
+impl<'a> VirtualMachine<'a> {
+    /// Check the arguments given to every `call` against the parameters of
+    /// the label, and store the arguments of `call n label(...)` into the new
+    /// frame right before the call.
+    pub fn pass_arguments(&mut self) -> Result<()> {
+        let params: HashMap<&'a str, usize> = self
+            .blocks
+            .iter()
+            .map(|(label, function)| (*label, function.args.len()))
+            .collect();
+
+        for function in self.blocks.values_mut() {
+            let mut code = Vec::with_capacity(function.instructions.len());
+
+            for inst in function.instructions.drain(..) {
+                let (Instruction::Call {
+                    label,
+                    args: Some(args),
+                    ..
+                }
+                | Instruction::StackCall {
+                    label,
+                    args: Some(args),
+                }) = inst
+                else {
+                    code.push(inst);
+                    continue;
+                };
+
+                // undefined labels are reported when generating the code
+                let given = args.iter().count();
+                if let Some(&expected) = params.get(label) {
+                    if given != expected {
+                        return Err(anyhow!(
+                            "`{label}` takes {expected} arguments, but `{}` passes {given}",
+                            function.name
+                        ));
+                    }
+                }
+
+                let Instruction::Call { offset_inc, .. } = inst else {
+                    // the frame is only known when running
+                    code.push(inst);
+                    continue;
+                };
+
+                // the call overwrites R0 anyway, so it carries the other
+                // arguments once its own is stored
+                let mut slots: Vec<(i32, Register)> = (0..).zip(args.iter()).collect();
+                slots.sort_by_key(|(_, reg)| *reg != Register::R0);
+                for (slot, reg) in slots {
+                    let addr = offset_inc
+                        .checked_add(1 + slot)
+                        .ok_or_else(|| anyhow!("argument of `{label}` is out of range"))?;
+                    if reg != Register::R0 {
+                        code.push(Instruction::Move {
+                            dst: Register::R0,
+                            src: reg,
+                        });
+                    }
+                    code.push(Instruction::Store { addr });
+                }
+                code.push(Instruction::Call {
+                    offset_inc,
+                    label,
+                    args: None,
+                });
+            }
+
+            function.instructions = code;
+        }
+
+        Ok(())
+    }
+}
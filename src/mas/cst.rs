@@ -0,0 +1,106 @@
+use std::fmt::{self, Display, Formatter};
+
+/// A word of source code together with the whitespace before it.
+#[derive(Clone, Copy, Debug)]
+pub struct Token<'a> {
+    pub leading: &'a str,
+    pub text: &'a str,
+}
+
+/// Lossless syntax tree of one source line. Writing it back with
+/// [`Display`] gives the original line, byte for byte.
+#[derive(Clone, Debug)]
+pub struct CstLine<'a> {
+    pub tokens: Vec<Token<'a>>,
+    /// Whitespace after the last token, before the comment.
+    pub trailing: &'a str,
+    /// Comment starting with `#`.
+    pub comment: Option<&'a str>,
+    /// Line terminator, empty for the last line.
+    pub newline: &'a str,
+}
+
+impl CstLine<'_> {
+    pub fn is_blank(&self) -> bool {
+        self.tokens.is_empty() && self.comment.is_none()
+    }
+
+    pub fn is_comment(&self) -> bool {
+        self.tokens.is_empty() && self.comment.is_some()
+    }
+}
+
+impl Display for CstLine<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for token in &self.tokens {
+            write!(f, "{}{}", token.leading, token.text)?;
+        }
+        write!(
+            f,
+            "{}{}{}",
+            self.trailing,
+            self.comment.unwrap_or_default(),
+            self.newline
+        )
+    }
+}
+
+/// Split the source into lossless lines.
+pub fn parse_cst(text: &str) -> Vec<CstLine<'_>> {
+    text.split_inclusive('\n').map(parse_cst_line).collect()
+}
+
+fn parse_cst_line(raw: &str) -> CstLine<'_> {
+    let content = raw.trim_end_matches(['\n', '\r']);
+    let newline = &raw[content.len()..];
+
+    let mut tokens = Vec::new();
+    let mut rest = content;
+    let mut comment = None;
+
+    loop {
+        let word = rest.trim_start();
+        let leading = &rest[..rest.len() - word.len()];
+
+        if word.is_empty() {
+            break;
+        }
+        if word.starts_with('#') {
+            comment = Some(word);
+            rest = leading;
+            break;
+        }
+
+        let len = token_len(word);
+        tokens.push(Token {
+            leading,
+            text: &word[..len],
+        });
+        rest = &word[len..];
+    }
+
+    CstLine {
+        tokens,
+        trailing: rest,
+        comment,
+        newline,
+    }
+}
+
+// a token ends at whitespace or a comment, unless inside a string
+fn token_len(word: &str) -> usize {
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for (offset, c) in word.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '#' if !in_string => return offset,
+            c if c.is_whitespace() && !in_string => return offset,
+            _ => {}
+        }
+    }
+    word.len()
+}
@@ -0,0 +1,182 @@
+use anyhow::Result;
+
+use super::{
+    cst::{parse_cst, CstLine},
+    parse::{parse_line, FuncOrInst},
+    Function,
+};
+
+/// Operands start after the mnemonic padded to this width and one space.
+const MNEMONIC_WIDTH: usize = 10;
+/// Trailing comments start at this column unless some code is longer.
+const COMMENT_COLUMN: usize = 26;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Blank,
+    Comment,
+    Label,
+    Instruction,
+}
+
+struct Formatted<'a> {
+    kind: Kind,
+    code: String,
+    comment: Option<&'a str>,
+    /// Comment lines that are not at column 0 are aligned with the
+    /// trailing comments.
+    aligned: bool,
+}
+
+/// Format Mas source in the canonical style. Fails if the source cannot be
+/// parsed. Formatting an already formatted source changes nothing.
+pub fn format_source(text: &str) -> Result<String> {
+    let mut lines = Vec::new();
+    // parameters of the current label, which instructions may name
+    let mut args = Vec::new();
+
+    for (line_index, (line, cst)) in text.lines().zip(parse_cst(text)).enumerate() {
+        let (kind, code) = match parse_line(line, line_index + 1, &args)? {
+            None if cst.is_blank() => (Kind::Blank, String::new()),
+            None => (Kind::Comment, String::new()),
+            Some(FuncOrInst::Function(func)) => {
+                let code = label_code(&func);
+                args = func.args;
+                (Kind::Label, code)
+            }
+            Some(FuncOrInst::Instruction(_)) => (Kind::Instruction, instruction_code(&cst)),
+        };
+
+        lines.push(Formatted {
+            kind,
+            code,
+            comment: cst.comment,
+            aligned: cst.is_comment() && !cst.trailing.is_empty(),
+        });
+    }
+
+    let comment_column = lines
+        .iter()
+        .filter(|line| line.comment.is_some() && !line.code.is_empty())
+        .map(|line| line.code.chars().count() + 2)
+        .fold(COMMENT_COLUMN, usize::max);
+
+    let mut out: Vec<&Formatted> = Vec::new();
+    let mut pending_blank = false;
+    let blank = Formatted {
+        kind: Kind::Blank,
+        code: String::new(),
+        comment: None,
+        aligned: false,
+    };
+
+    for line in &lines {
+        match line.kind {
+            Kind::Blank => {
+                pending_blank = !out.is_empty();
+                continue;
+            }
+            // keep one blank line before every label and the comments
+            // attached to it
+            Kind::Label if !pending_blank => {
+                let attached = out
+                    .iter()
+                    .rev()
+                    .take_while(|line| line.kind == Kind::Comment)
+                    .count();
+                let start = out.len() - attached;
+                // the comments may already follow a blank line
+                if start > 0 && out[start - 1].kind != Kind::Blank {
+                    out.insert(start, &blank);
+                }
+            }
+            _ if pending_blank => out.push(&blank),
+            _ => {}
+        }
+
+        pending_blank = false;
+        out.push(line);
+    }
+
+    let mut formatted = String::new();
+    for line in out {
+        match (line.comment, line.code.is_empty()) {
+            (None, _) => formatted += &line.code,
+            (Some(comment), true) if line.aligned => {
+                formatted += &format!("{:comment_column$}{comment}", "")
+            }
+            (Some(comment), true) => formatted += comment,
+            (Some(comment), false) => {
+                formatted += &format!("{:<comment_column$}{comment}", line.code)
+            }
+        }
+        formatted += "\n";
+    }
+
+    Ok(formatted)
+}
+
+fn label_code(func: &Function) -> String {
+    let mut code = String::new();
+    for hook in &func.hooks {
+        code += &format!("{hook} ");
+    }
+    code += func.name;
+    if !func.args.is_empty() {
+        code += &format!("({})", func.args.join(", "));
+    }
+    code + ":"
+}
+
+fn instruction_code(cst: &CstLine) -> String {
+    let mut tokens = cst.tokens.iter().map(|token| token.text);
+    let mnemonic = tokens.next().unwrap_or_default();
+    let operands: Vec<&str> = tokens.collect();
+
+    if operands.is_empty() {
+        mnemonic.to_string()
+    } else {
+        format!("{mnemonic:<MNEMONIC_WIDTH$} {}", operands.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format_source;
+
+    const SOURCES: [&str; 2] = [
+        include_str!("../../fibonacci.mas"),
+        include_str!("../../simple.mas"),
+    ];
+
+    fn assert_stable(text: &str) {
+        let once = format_source(text).unwrap();
+        let twice = format_source(&once).unwrap();
+        assert_eq!(once, twice, "formatting is not idempotent for:\n{text}");
+    }
+
+    #[test]
+    fn formatting_is_idempotent() {
+        for text in SOURCES {
+            assert_stable(text);
+        }
+        assert_stable("a:\nret\n# c\nb:\nret\n");
+        assert_stable("a:\nret\n\n# c\nb:\nret\n");
+        assert_stable("# header\na:\nret\n\n\n# c\n# d\n\nb:\nret\n");
+    }
+
+    #[test]
+    fn canonical_source_is_unchanged() {
+        for text in [
+            "a:\nret\n\nb:\nret\n",
+            "a:\nret\n\n# c\nb:\nret\n",
+            "a:\nset        R0 1\nret\n",
+        ] {
+            assert_eq!(format_source(text).unwrap(), text);
+        }
+        // the sources of the repository are kept formatted
+        for text in SOURCES {
+            assert_eq!(format_source(text).unwrap(), text);
+        }
+    }
+}
@@ -0,0 +1,250 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    bootstrap::{alloc_cost, bank_cost, dump_cost, free_cost, search_cost, DISPATCH_FANOUT},
+    manifest::BuildConfig,
+    mas::{Hook, Instruction, VirtualMachine},
+};
+
+use super::{direct_cell, threads::SWITCH_COST, trap};
+
+/// Commands of the check in front of a label, run by the code jumping to it.
+/// An `execute` counts twice when it runs its command.
+pub(super) const CHECK_COST: usize = 7;
+
+/// Limit on the commands a program runs before it yields. The program is
+/// charged `reserve` commands at every jump into a label and every `ret`,
+/// and yields there once the charge would reach the budget.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Budget {
+    /// `BUDGET` values from which the program yields instead of continuing.
+    pub limit: usize,
+    /// Commands the code may run until the next check, in the worst case.
+    pub reserve: usize,
+}
+
+/// Where the program has to yield to stay within the command budget.
+pub(super) struct Plan<'a> {
+    pub budget: Budget,
+    /// Instructions the program yields before, by label and index.
+    pub yields: HashSet<(&'a str, usize)>,
+}
+
+impl<'a> VirtualMachine<'a> {
+    /// Estimate the commands run between two checks along every path of the
+    /// reachable labels, and split the code that could exceed half of the
+    /// budget on its own.
+    pub(super) fn plan_budget(
+        &self,
+        offsets: &HashMap<&'a str, Option<i32>>,
+        entries: &[String],
+        config: &BuildConfig,
+    ) -> Result<Option<Plan<'a>>> {
+        let Some(budget) = config.command_budget else {
+            return Ok(None);
+        };
+
+        // every instruction creates at most a continuation, a yield before
+        // it and a check in front of its target
+        let instructions: usize = offsets
+            .keys()
+            .map(|l| self.blocks[l].instructions.len())
+            .sum();
+        let exec_size = 3 * instructions + 1;
+
+        // resuming through the tick function and `FUNC_EXEC`
+        let mut overhead = 7 + search_cost(exec_size, DISPATCH_FANOUT);
+        if config.threads > 1 {
+            overhead += SWITCH_COST;
+        }
+        // or setting up the frame of the handler after a trap
+        if self.blocks.values().any(|f| f.hooks.contains(&Hook::Trap)) {
+            overhead += trap::setup_cost(config.threads, config.memory().direct_cost());
+        }
+        let segment_limit = (budget / 2)
+            .checked_sub(overhead)
+            .filter(|l| *l > 0)
+            .ok_or_else(|| anyhow!("command budget {budget} is too small"))?;
+
+        let mut yields = HashSet::new();
+        let mut longest = 0;
+
+        for (&label, &offset) in offsets {
+            let mut spent = match entries.iter().any(|e| e == label) {
+                true => 3,
+                false => 0,
+            };
+            let instructions = &self.blocks[label].instructions;
+            let mut code = instructions.as_slice();
+
+            while let [inst, rest @ ..] = code {
+                let index = instructions.len() - code.len();
+                code = rest;
+
+                let (cost, next) = match self.fused_branch(*inst, rest, config) {
+                    Some(_) => {
+                        code = &rest[1..];
+                        (3 + CHECK_COST, None)
+                    }
+                    None => cost(*inst, offset, config, exec_size),
+                };
+
+                if cost > segment_limit {
+                    return Err(anyhow!(
+                        "command budget {budget} is too small, an instruction of `{label}` runs {cost} commands"
+                    ));
+                }
+                if spent + cost > segment_limit {
+                    yields.insert((label, index));
+                    longest = longest.max(spent + 2);
+                    spent = 0;
+                }
+                spent += cost;
+
+                if let Some(next) = next {
+                    longest = longest.max(spent);
+                    spent = next;
+                }
+            }
+
+            // stopping at the end of the label
+            longest = longest.max(spent + 2);
+        }
+
+        let reserve = longest + overhead;
+        Ok(Some(Plan {
+            budget: Budget {
+                limit: budget - reserve + 1,
+                reserve,
+            },
+            yields,
+        }))
+    }
+
+    /// Reachable labels on a loop that runs raw `function` commands, whose
+    /// cost cannot be estimated.
+    pub(super) fn unbounded_loops(&self, offsets: &HashMap<&'a str, Option<i32>>) -> Vec<&'a str> {
+        let mut labels: Vec<&'a str> = offsets
+            .keys()
+            .copied()
+            .filter(|l| {
+                self.blocks[l].instructions.iter().any(
+                    |inst| matches!(inst, Instruction::RawCommand(cmd) if cmd.contains("function")),
+                )
+            })
+            .filter(|l| self.on_loop(l))
+            .collect();
+        labels.sort();
+        labels
+    }
+
+    fn on_loop(&self, label: &str) -> bool {
+        let mut visited = HashSet::new();
+        let mut worklist = vec![label];
+
+        while let Some(l) = worklist.pop() {
+            for target in self.blocks[l]
+                .instructions
+                .iter()
+                .flat_map(Instruction::labels)
+            {
+                if target == label {
+                    return true;
+                }
+                if visited.insert(target) {
+                    worklist.push(target);
+                }
+            }
+        }
+        false
+    }
+}
+
+/// Commands run by the code generated for `inst` in the worst case, not
+/// counting the label it jumps to. Also returns the commands the code
+/// continues with if it ends the current stretch between two checks.
+fn cost(
+    inst: Instruction,
+    offset: Option<i32>,
+    config: &BuildConfig,
+    exec_size: usize,
+) -> (usize, Option<usize>) {
+    // with run time checks, a search is followed by a test for a trap, and
+    // a call by the test of its frame
+    let checked = |commands| if config.checks { commands } else { 0 };
+    let memory = config.memory();
+    let search = memory.search_cost();
+    let access = |addr| match direct_cell(offset, addr, config.memory_size) {
+        Some(_) => memory.direct_cost(),
+        None => 3 + search + checked(2),
+    };
+    let frame_check = checked(config.threads + 4);
+    // moving the pointer, swapping the register with R0 and back
+    let stack_access = 5 + search;
+
+    // with run time checks, the number of an instruction that may raise a
+    // trap is recorded first
+    let site = match config.checks && trap::may_trap(inst, offset, config.memory_size) {
+        true => 2,
+        false => 0,
+    };
+
+    let (commands, next) = match inst {
+        Instruction::Branch(_) => (1 + CHECK_COST, Some(0)),
+        Instruction::BranchIf(_) | Instruction::BranchIfNot(_) => (3 + CHECK_COST, None),
+        Instruction::Switch { cases, .. } => (
+            1 + search_cost(cases.iter().count(), DISPATCH_FANOUT) + CHECK_COST,
+            Some(0),
+        ),
+        Instruction::Compare(_) | Instruction::CompareIn { .. } => (4, None),
+        Instruction::Load { addr } | Instruction::Store { addr } => (access(addr), None),
+        // continues with the return block restoring the offset
+        Instruction::Call { offset_inc, .. } => {
+            (3 + access(offset_inc) + frame_check + CHECK_COST, Some(1))
+        }
+        Instruction::StackCall { args, .. } => {
+            let saved = config.saved_registers.len() * stack_access;
+            let stored = args.map_or(0, |args| args.iter().count()) * stack_access;
+            (
+                stored + saved + 9 + 2 * (1 + search) + frame_check + CHECK_COST,
+                Some(saved + 4 + 1 + search),
+            )
+        }
+        Instruction::Push(_) | Instruction::Pop(_) => (stack_access + checked(2), None),
+        Instruction::LoadPtr(_) | Instruction::StorePtr(_) => (2 + search + checked(2), None),
+        Instruction::Alloc => (1 + alloc_cost(search, config.heap_size), None),
+        Instruction::Free => (1 + free_cost(search, config.heap_size) + checked(4), None),
+        Instruction::Bank => (1 + bank_cost(&memory) + checked(4), None),
+        Instruction::DebugDump { .. } => (3 + dump_cost(&memory, config.dump_size), None),
+        // also searches the call table, which is as large as `FUNC_EXEC`
+        Instruction::IndirectCall { offset_inc, .. } => (
+            4 + access(offset_inc)
+                + search_cost(exec_size, DISPATCH_FANOUT)
+                + frame_check
+                + CHECK_COST,
+            Some(1),
+        ),
+        Instruction::Yield | Instruction::Sleep(_) => (3, Some(0)),
+        // a test for every thread, and a copy of its state for the new one
+        Instruction::Spawn(_) => (config.threads + 14 + memory.direct_cost(), None),
+        Instruction::Join(_) => (2 * config.threads + 8, None),
+        Instruction::Return if offset == Some(0) => (2, Some(0)),
+        Instruction::Return => (
+            5 + access(-1) + search_cost(exec_size, DISPATCH_FANOUT),
+            Some(0),
+        ),
+        // may be an `execute`
+        Instruction::RawCommand(_) => (2, None),
+        Instruction::Move { .. }
+        | Instruction::Set { .. }
+        | Instruction::AddConst { .. }
+        | Instruction::Addr { .. }
+        | Instruction::Calculate(_)
+        | Instruction::Random { .. }
+        | Instruction::Debug { .. }
+        | Instruction::Log(_) => (1, None),
+    };
+    (commands + site, next)
+}
@@ -1,26 +1,34 @@
 use std::{
     borrow::Cow,
-    cell::RefCell,
-    collections::{hash_map::Entry, HashMap},
-    fs,
-    path::Path,
+    cell::{Cell, RefCell},
+    collections::{hash_map::Entry, HashMap, HashSet},
 };
 
-use anyhow::Result;
+use crate::{
+    bootstrap::{
+        gen_search, Memory, BAD_PC, BAD_POINTER, BUDGET, DISPATCH_FANOUT, FUNC_EXEC, FUNC_ICALL,
+        HALTED, PREFIX, PROGRAM_COUNTER,
+    },
+    manifest::BuildConfig,
+    pack::{stable_hash, Output},
+};
+
+use crate::mas::Register;
 
-use crate::bootstrap::{FUNC_EXEC, PREFIX, PROGRAM_COUNTER};
+use super::{budget::Budget, Removed};
 
 pub struct Block<'a> {
-    id: u64,
+    /// Value of `PROGRAM_COUNTER` that runs the block through `FUNC_EXEC`,
+    /// only assigned to blocks that need it.
+    id: Cell<Option<u64>>,
     content: RefCell<String>,
     fn_name: Cow<'a, str>,
+    anonymous: bool,
+    /// Labels of the blocks this block may continue with.
+    successors: RefCell<Vec<String>>,
 }
 
 impl Block<'_> {
-    pub fn id(&self) -> u64 {
-        self.id
-    }
-
     pub fn push_str(&self, s: impl AsRef<str>) {
         self.content.borrow_mut().push_str(s.as_ref())
     }
@@ -30,34 +38,134 @@ impl Block<'_> {
     }
 }
 
+/// Dispatch of a `switch` on the value of a register.
+struct Switch {
+    /// Label of the block running the switch.
+    owner: String,
+    fn_name: String,
+    reg: &'static str,
+    /// Function names of the cases and the default.
+    cases: Vec<String>,
+    default: String,
+}
+
+/// Label whose id is taken by `addr`, so `FUNC_ICALL` can call it.
+struct Pointer {
+    /// Label of the block taking the id.
+    owner: String,
+    id: u64,
+    fn_name: String,
+}
+
 pub(super) struct Context<'a> {
+    namespace: Option<&'a str>,
+    memory: Memory,
+    heap_size: usize,
+    threads: usize,
+    budget: Option<Budget>,
+    runtime_checks: bool,
+    frame_sizes: HashMap<&'a str, i32>,
+    saved_registers: Vec<Register>,
     mangle_uuid: u64,
     anonymous_pool: u64,
     label_id_pool: u64,
     labels: HashMap<Cow<'a, str>, Block<'a>>,
+    /// Anonymous labels checking the budget in front of a label.
+    checks: HashMap<String, String>,
+    switches: Vec<Switch>,
+    pointers: Vec<Pointer>,
+    /// Labels of the blocks running `FUNC_ICALL`.
+    indirect_calls: Vec<String>,
+    /// Labels resumed by code outside of the labels, like the entries.
+    roots: Vec<String>,
+    trap_sites: usize,
 }
 
 impl<'a> Context<'a> {
-    pub fn new() -> Self {
+    pub fn new(
+        config: &'a BuildConfig,
+        budget: Option<Budget>,
+        frame_sizes: HashMap<&'a str, i32>,
+    ) -> Self {
+        let namespace = config.namespace.as_deref();
         Self {
-            mangle_uuid: rand::random(),
+            namespace,
+            memory: config.memory(),
+            heap_size: config.heap_size,
+            threads: config.threads,
+            budget,
+            runtime_checks: config.checks,
+            frame_sizes,
+            saved_registers: config.saved_registers.clone(),
+            // stays the same between builds, so unchanged functions keep their files
+            mangle_uuid: stable_hash(namespace.unwrap_or_default(), "mangle"),
             anonymous_pool: 0,
             label_id_pool: 0, // 0 for empty block
             labels: HashMap::new(),
+            checks: HashMap::new(),
+            switches: Vec::new(),
+            pointers: Vec::new(),
+            indirect_calls: Vec::new(),
+            roots: Vec::new(),
+            trap_sites: 0,
         }
     }
 
-    fn gen_block(&mut self, fn_name: Cow<'a, str>) -> Block<'a> {
-        let new_id = self
-            .label_id_pool
-            .checked_add(1)
-            .expect("label id pool was full");
-        self.label_id_pool = new_id;
+    pub fn memory_size(&self) -> usize {
+        self.memory.size
+    }
+
+    pub fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    pub fn heap_size(&self) -> usize {
+        self.heap_size
+    }
 
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Cells of the memory before the heap every thread gets for its
+    /// frames, the `nth` thread starting at `nth * stride()`.
+    pub fn stride(&self) -> usize {
+        (self.memory.size - self.heap_size) / self.threads
+    }
+
+    /// Whether the code checks for runtime errors.
+    pub fn runtime_checks(&self) -> bool {
+        self.runtime_checks
+    }
+
+    /// Number of the next instruction that may raise a trap, 0 is left for
+    /// an invalid program counter.
+    pub fn trap_site(&mut self) -> usize {
+        self.trap_sites += 1;
+        self.trap_sites
+    }
+
+    /// Slots `call label` reserves for the frame of `label`.
+    pub fn frame_size(&self, label: &str) -> i32 {
+        self.frame_sizes.get(label).copied().unwrap_or_default()
+    }
+
+    /// Registers kept on the stack during `call label`.
+    pub fn saved_registers(&self) -> Vec<Register> {
+        self.saved_registers.clone()
+    }
+
+    pub fn budget(&self) -> Option<Budget> {
+        self.budget
+    }
+
+    fn gen_block(&mut self, fn_name: Cow<'a, str>, anonymous: bool) -> Block<'a> {
         Block {
-            id: new_id,
+            id: Cell::new(None),
             content: Default::default(),
             fn_name,
+            anonymous,
+            successors: Default::default(),
         }
     }
 
@@ -65,12 +173,8 @@ impl<'a> Context<'a> {
         format!("{PREFIX}_{label}_mangled_{:x}", self.mangle_uuid)
     }
 
-    pub fn insert_label(&mut self, key: &'a str, mangle: bool) -> &mut Block<'a> {
-        let block: Block<'a> = self.gen_block(if mangle {
-            self.mangle(key).into()
-        } else {
-            key.into()
-        });
+    pub fn insert_label(&mut self, key: &'a str) -> &mut Block<'a> {
+        let block: Block<'a> = self.gen_block(self.mangle(key).into(), false);
 
         match self.labels.entry(key.into()) {
             Entry::Occupied(_) => panic!("label is exists"),
@@ -78,6 +182,29 @@ impl<'a> Context<'a> {
         }
     }
 
+    /// Add the function starting the program at the label `key`, which
+    /// keeps the name of the label so it can be invoked by `/function`. It
+    /// runs `prologue` first, which code jumping to the label skips.
+    pub fn insert_entry(&mut self, key: &str, prologue: &str) {
+        let fn_name = match self.namespace {
+            Some(ns) => format!("{ns}/{key}"),
+            None => key.to_string(),
+        };
+        let entry = entry_key(key);
+        let block = self.gen_block(fn_name.into(), true);
+        self.labels.insert(entry.clone().into(), block);
+
+        let function = self.jump(&entry, key).to_string();
+        self.get_label(&entry)
+            .push_str(format!("{prologue}function {function}\n"));
+        self.roots.push(entry);
+    }
+
+    /// Function name of the entry `key`, added by `insert_entry`.
+    pub fn entry(&self, key: &str) -> &str {
+        self.get_label(&entry_key(key)).fn_name()
+    }
+
     pub fn get_label(&self, key: &str) -> &Block<'a> {
         self.labels.get(key).expect("label not defined")
     }
@@ -87,36 +214,224 @@ impl<'a> Context<'a> {
         self.anonymous_pool += 1;
 
         let label = format!("_anonymous_{id:x}");
-        let block = self.gen_block(self.mangle(&label).into());
+        let block = self.gen_block(self.mangle(&label).into(), true);
         self.labels.insert(label.clone().into(), block);
         label
     }
 
-    pub fn generate(&self, save_as: impl AsRef<Path>) -> Result<()> {
-        let mut id_table: Vec<&Block> = self.labels.values().collect();
-        id_table.sort_by_key(|func| func.id);
+    /// Function name of `to`, which `from` runs directly.
+    pub fn jump(&self, from: &str, to: &str) -> &str {
+        self.get_label(from).successors.borrow_mut().push(to.to_string());
+        self.get_label(to).fn_name()
+    }
+
+    /// Function name that runs the label `to` from `from`. With a command
+    /// budget, it first charges the program and yields to `to` instead if
+    /// the budget is used up.
+    pub fn enter(&mut self, from: &str, to: &str) -> String {
+        let Some(budget) = self.budget else {
+            return self.jump(from, to).to_string();
+        };
+
+        let check = match self.checks.get(to) {
+            Some(check) => check.clone(),
+            None => {
+                let check = self.new_anonymous_label();
+                let id = self.dispatch_id(&check, to);
+                let exceeded = format!(
+                    "execute if score {PREFIX} {BUDGET} matches {}.. run",
+                    budget.limit
+                );
+                let function = self.jump(&check, to);
+                self.get_label(&check).push_str(format!(
+                    "{exceeded} scoreboard players set {PREFIX} {PROGRAM_COUNTER} {id}\n\
+                    {exceeded} scoreboard players set {PREFIX} {HALTED} 1\n\
+                    scoreboard players add {PREFIX} {BUDGET} {}\n\
+                    execute if score {PREFIX} {HALTED} matches 0 run function {function}\n",
+                    budget.reserve
+                ));
+                self.checks.insert(to.to_string(), check.clone());
+                check
+            }
+        };
+        self.jump(from, &check).to_string()
+    }
+
+    /// Function name of a new dispatch for `from`, that runs the function of
+    /// `cases` at the index held by `reg`, or `default`.
+    pub fn switch(
+        &mut self,
+        from: &str,
+        reg: &'static str,
+        cases: Vec<String>,
+        default: String,
+    ) -> String {
+        let fn_name = self.mangle(&format!("_switch_{:x}", self.switches.len()));
+        self.switches.push(Switch {
+            owner: from.to_string(),
+            fn_name: fn_name.clone(),
+            reg,
+            cases,
+            default,
+        });
+        fn_name
+    }
+
+    /// Id of the label `to`, which `FUNC_ICALL` calls once `from` has taken
+    /// it.
+    pub fn pointer(&mut self, from: &str, to: &str) -> u64 {
+        let id = self.dispatch_id(from, to);
+        let fn_name = self.enter(from, to);
+        self.pointers.push(Pointer {
+            owner: from.to_string(),
+            id,
+            fn_name,
+        });
+        id
+    }
+
+    /// `FUNC_ICALL`, which `from` runs to call the label whose id is in
+    /// `PROGRAM_COUNTER`.
+    pub fn indirect_call(&mut self, from: &str) -> &'static str {
+        self.indirect_calls.push(from.to_string());
+        FUNC_ICALL
+    }
+
+    /// `PROGRAM_COUNTER` value that runs `to` through `FUNC_EXEC`, for when
+    /// `from` continues with it later.
+    pub fn dispatch_id(&mut self, from: &str, to: &str) -> u64 {
+        self.get_label(from).successors.borrow_mut().push(to.to_string());
+        self.id(to)
+    }
 
-        crate::bootstrap::gen_bin_search(
-            save_as.as_ref(),
+    /// `PROGRAM_COUNTER` value that runs `to` through `FUNC_EXEC`, for code
+    /// outside of the labels.
+    pub fn root_id(&mut self, to: &str) -> u64 {
+        self.roots.push(to.to_string());
+        self.id(to)
+    }
+
+    fn id(&mut self, to: &str) -> u64 {
+        let block = self.get_label(to);
+        if let Some(id) = block.id.get() {
+            return id;
+        }
+
+        let new_id = self
+            .label_id_pool
+            .checked_add(1)
+            .expect("label id pool was full");
+        self.label_id_pool = new_id;
+        self.get_label(to).id.set(Some(new_id));
+        new_id
+    }
+
+    /// Write the blocks reachable from `entries` and the dispatch function
+    /// for those that have an id.
+    pub fn generate(&self, output: &mut Output, entries: &[String]) -> Removed {
+        let mut reachable = HashSet::new();
+        let mut worklist: Vec<&str> = entries
+            .iter()
+            .chain(&self.roots)
+            .map(String::as_str)
+            .collect();
+
+        while let Some(label) = worklist.pop() {
+            if reachable.insert(label) {
+                for successor in self.get_label(label).successors.borrow().iter() {
+                    let (key, _) = self
+                        .labels
+                        .get_key_value(successor.as_str())
+                        .expect("label not defined");
+                    worklist.push(key);
+                }
+            }
+        }
+
+        let mut id_table = HashMap::new();
+        for (label, block) in &self.labels {
+            if let (true, Some(id)) = (reachable.contains(label.as_ref()), block.id.get()) {
+                id_table.insert(id, block.fn_name());
+            }
+        }
+
+        // the code that runs may change the value searched for
+        let guard = format!("if score {PREFIX} {HALTED} matches 0");
+
+        gen_search(
+            output,
             FUNC_EXEC,
             PROGRAM_COUNTER,
-            self.labels.len() + 1,
-            |nth| match nth.checked_sub(1) {
-                None => format!("function {PREFIX}_nonexistence_fn"),
-                Some(nth2) => self
-                    .labels
-                    .get(id_table[nth2].fn_name())
-                    .unwrap()
-                    .fn_name()
-                    .to_string(),
+            Some(&guard),
+            self.label_id_pool as usize + 1,
+            DISPATCH_FANOUT,
+            self.runtime_checks.then(|| BAD_PC.raise()).as_deref(),
+            |nth| match id_table.get(&(nth as u64)) {
+                Some(fn_name) => format!("function {fn_name}"),
+                // 0 is the empty block, nothing left to execute
+                None => format!("scoreboard players set {PREFIX} {HALTED} 1"),
             },
-        )?;
+        );
+
+        for switch in &self.switches {
+            if reachable.contains(switch.owner.as_str()) {
+                gen_search(
+                    output,
+                    &switch.fn_name,
+                    switch.reg,
+                    Some(&guard),
+                    switch.cases.len(),
+                    DISPATCH_FANOUT,
+                    Some(&format!("function {}", switch.default)),
+                    |nth| format!("function {}", switch.cases[nth]),
+                );
+            }
+        }
+
+        if self
+            .indirect_calls
+            .iter()
+            .any(|l| reachable.contains(l.as_str()))
+        {
+            let pointers: HashMap<u64, &str> = self
+                .pointers
+                .iter()
+                .filter(|p| reachable.contains(p.owner.as_str()))
+                .map(|p| (p.id, p.fn_name.as_str()))
+                .collect();
+            let bad_pointer = BAD_POINTER.raise();
 
-        for block in self.labels.values() {
-            let mut path = save_as.as_ref().join(&*block.fn_name);
-            path.set_extension("mcfunction");
-            fs::write(path, &*block.content.borrow())?;
+            gen_search(
+                output,
+                FUNC_ICALL,
+                PROGRAM_COUNTER,
+                Some(&guard),
+                self.label_id_pool as usize + 1,
+                DISPATCH_FANOUT,
+                Some(&bad_pointer),
+                |nth| match pointers.get(&(nth as u64)) {
+                    Some(fn_name) => format!("function {fn_name}"),
+                    // ids of blocks that are not labels taken by `addr`
+                    None => bad_pointer.clone(),
+                },
+            );
         }
-        Ok(())
+
+        let mut removed = Removed::default();
+        for (label, block) in &self.labels {
+            if reachable.contains(label.as_ref()) {
+                output.add_function(&block.fn_name, block.content.borrow().as_str());
+            } else if block.anonymous {
+                removed.blocks += 1;
+            } else {
+                removed.labels.push(label.to_string());
+            }
+        }
+        removed
     }
 }
+
+/// Key of the block `Context::insert_entry` adds for the label `key`.
+fn entry_key(key: &str) -> String {
+    format!("_entry_{key}")
+}
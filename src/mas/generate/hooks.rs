@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    bootstrap::{BOOTED, EVERY, FUNC_BOOT, FUNC_HOOKS, PREFIX, PROGRAM_COUNTER},
+    manifest::BuildConfig,
+    mas::{Hook, Instruction, VirtualMachine},
+    pack::Output,
+};
+
+use super::ctx::Context;
+
+impl<'a> VirtualMachine<'a> {
+    /// Reject labels bound to events if the program can be suspended, since
+    /// they would be skipped while it is.
+    pub(super) fn check_hooks(
+        &self,
+        offsets: &HashMap<&'a str, Option<i32>>,
+        config: &BuildConfig,
+        handler: Option<&str>,
+    ) -> Result<()> {
+        let Some((name, hook)) = self.blocks.values().find_map(|f| {
+            let hook = f.hooks.iter().find(|h| **h != Hook::Trap)?;
+            Some((f.name, hook))
+        }) else {
+            return Ok(());
+        };
+
+        let mut labels: Vec<&str> = offsets.keys().copied().collect();
+        labels.sort();
+        let suspends = labels.into_iter().find_map(|l| {
+            let instructions = &self.blocks[l].instructions;
+            let inst = instructions.iter().find_map(|inst| match inst {
+                Instruction::Yield => Some("yield"),
+                Instruction::Sleep(_) => Some("sleep"),
+                Instruction::Join(_) => Some("join"),
+                _ => None,
+            })?;
+            Some(format!("`{inst}` in `{l}` suspends it"))
+        });
+        let reason = if config.command_budget.is_some() {
+            "`command_budget` suspends it".to_string()
+        } else if config.threads > 1 {
+            "other threads suspend it".to_string()
+        } else if let Some(handler) = handler {
+            format!("`@on_trap` label `{handler}` suspends it until the next tick")
+        } else if let Some(suspends) = suspends {
+            suspends
+        } else {
+            return Ok(());
+        };
+        Err(anyhow!(
+            "`{hook}` label `{name}` would be skipped while the program is suspended, and {reason}"
+        ))
+    }
+
+    /// Write the function that runs the labels bound to events every tick,
+    /// returns whether there are any. Nothing runs until `FUNC_INIT` has
+    /// initialized the memory. A label only starts if the program is not
+    /// suspended, so it never overwrites the state of another one.
+    pub(super) fn write_hooks(&self, output: &mut Output, ctx: &Context) -> bool {
+        let mut on_load = String::new();
+        let mut on_tick = String::new();
+
+        for function in self.blocks.values().filter(|f| !f.hooks.is_empty()) {
+            let name = function.name;
+            let fn_name = ctx.entry(name);
+            let start = format!(
+                "execute if score {PREFIX} {PROGRAM_COUNTER} matches 0 run function {fn_name}\n"
+            );
+
+            for hook in &function.hooks {
+                match hook {
+                    // started by `FUNC_TRAP`
+                    Hook::Trap => {}
+                    Hook::Load => on_load += &start,
+                    Hook::Tick => on_tick += &start,
+                    Hook::Every(ticks) => {
+                        on_tick += &format!(
+                            "scoreboard players add {name} {EVERY} 1\n\
+                            execute if score {name} {EVERY} matches {ticks}.. if score {PREFIX} {PROGRAM_COUNTER} matches 0 run scoreboard players set {name} {EVERY} 0\n\
+                            execute if score {name} {EVERY} matches 0 run function {fn_name}\n"
+                        )
+                    }
+                }
+            }
+        }
+
+        if on_load.is_empty() && on_tick.is_empty() {
+            return false;
+        }
+
+        output.add_function(
+            FUNC_BOOT,
+            format!("scoreboard players set {PREFIX} {BOOTED} 1\n{on_load}"),
+        );
+        // a missing objective fails both tests
+        let mut content =
+            format!("execute if score {PREFIX} {BOOTED} matches 0 run function {FUNC_BOOT}\n");
+        if !on_tick.is_empty() {
+            let tick = format!("{FUNC_HOOKS}/Tick");
+            content +=
+                &format!("execute if score {PREFIX} {BOOTED} matches 1 run function {tick}\n");
+            output.add_function(&tick, on_tick);
+        }
+        output.add_function(FUNC_HOOKS, content);
+        true
+    }
+}
@@ -1,40 +1,227 @@
-use std::{borrow::Cow, path::Path};
+use std::borrow::Cow;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 
 use crate::{
     bootstrap::{
-        FUNC_EXEC, FUNC_LOAD, FUNC_STORE, MEM_OFFSET, MEM_POINTER, PREFIX, PROGRAM_COUNTER, REG_R0,
-        REG_R1, REG_R2, REG_R3,
+        thread_player, Memory, BUDGET, FUNC_ALLOC, FUNC_BANK, FUNC_DUMP, FUNC_EXEC, FUNC_FREE,
+        FUNC_HOOKS, FUNC_LOAD, FUNC_SPAWN, FUNC_STORE, FUNC_TICK, HALTED, MEM_OFFSET, MEM_POINTER,
+        PREFIX, PROGRAM_COUNTER, REG_COND, REG_R0, REG_R1, REG_R2, REG_R3, STACK_POINTER, TIMER,
     },
-    mas::{CalcOp, ExprCmpIn},
+    manifest::BuildConfig,
+    mas::{CalcOp, Delay, ExprCmpIn, Hook},
+    pack::{self, Output},
 };
 
 use self::ctx::Context;
 
-use super::{CmpOp, Instruction, Register, VirtualMachine};
+use super::{Args, CmpOp, Instruction, Register, VirtualMachine};
 
+mod budget;
 mod ctx;
+mod hooks;
+mod offset;
+mod stack;
+mod threads;
+mod trap;
+
+/// What the code generator has to say about a program.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub removed: Removed,
+    pub warnings: Vec<String>,
+}
+
+/// Code left out of the pack because it can never run.
+#[derive(Debug, Default)]
+pub struct Removed {
+    pub labels: Vec<String>,
+    /// Continuation blocks created by the code generator.
+    pub blocks: usize,
+}
+
+impl<'a> VirtualMachine<'a> {
+    /// Write the labels reachable from the entries, returns what was left
+    /// out.
+    pub fn generate(&self, output: &mut Output, config: &BuildConfig) -> Result<Report> {
+        let mut entries = config.entries.clone();
+        for entry in &entries {
+            if !self.blocks.contains_key(entry.as_str()) {
+                return Err(anyhow!("entry label `{entry}` is not defined"));
+            }
+        }
+
+        for function in self.blocks.values() {
+            for label in function.instructions.iter().flat_map(Instruction::labels) {
+                if !self.blocks.contains_key(label) {
+                    return Err(anyhow!(
+                        "label `{label}` used in `{}` is not defined",
+                        function.name
+                    ));
+                }
+            }
+        }
+
+        // labels bound to events start the program like entries
+        for function in self.blocks.values() {
+            let starts = function.hooks.iter().any(|h| *h != Hook::Trap);
+            if starts && !entries.iter().any(|e| e == function.name) {
+                entries.push(function.name.to_string());
+            }
+        }
+
+        if config.threads > 1 && config.stack_size() / config.threads < 2 {
+            return Err(anyhow!(
+                "memory size {} is too small for {} threads",
+                config.stack_size(),
+                config.threads
+            ));
+        }
+
+        let handler = self.trap_handler(&entries)?;
+
+        // labels never reached from an entry are left out
+        let offsets = self.static_offsets(&entries, handler);
+        self.check_hooks(&offsets, config, handler)?;
+        self.check_stack_frames(&offsets)?;
+        let frame_sizes = self.frame_sizes();
+        let plan = self.plan_budget(&offsets, &entries, config)?;
+        let budget = plan.as_ref().map(|p| p.budget);
+        let mut ctx = Context::new(config, budget, frame_sizes);
 
-impl VirtualMachine<'_> {
-    pub fn generate(&self, save_as: impl AsRef<Path>) -> Result<()> {
-        let mut ctx = Context::new();
+        for label in self.blocks.keys().filter(|l| offsets.contains_key(*l)) {
+            ctx.insert_label(label);
+        }
 
-        for label in self.blocks.keys() {
-            ctx.insert_label(*label, if *label == "main" { false } else { true });
+        // only a start from outside sets up the program, `b` and `call` keep
+        // the frame and the budget of the code jumping to the entry
+        let uses_stack = self.uses_stack(&offsets);
+        for entry in &entries {
+            // the handler or a stopped call may have left the offset of its frame
+            let mut prologue = format!(
+                "scoreboard players set {PREFIX} {HALTED} 0\n\
+                scoreboard players set {PREFIX} {MEM_OFFSET} 0\n"
+            );
+            if uses_stack {
+                // entries run at offset 0
+                prologue += &format!(
+                    "scoreboard players set {PREFIX} {STACK_POINTER} {}\n",
+                    ctx.frame_size(entry)
+                );
+            }
+            if let Some(budget) = budget {
+                prologue += &format!(
+                    "scoreboard players set {PREFIX} {BUDGET} {}\n",
+                    budget.reserve
+                );
+            }
+            ctx.insert_entry(entry, &prologue);
         }
 
         for (label, function) in &self.blocks {
+            let Some(&offset) = offsets.get(label) else {
+                continue;
+            };
+            let name = *label;
             let mut label = Cow::Borrowed(*label);
+            let mut code = function.instructions.as_slice();
+
+            while let [inst, rest @ ..] = code {
+                let index = function.instructions.len() - code.len();
+                code = rest;
+
+                if plan
+                    .as_ref()
+                    .is_some_and(|p| p.yields.contains(&(name, index)))
+                {
+                    if let Some(new_l) = translate(&label, &mut ctx, offset, Instruction::Yield)? {
+                        label = Cow::Owned(new_l);
+                    }
+                }
 
-            for inst in &function.instructions {
-                if let Some(new_l) = translate(&label, &mut ctx, *inst)? {
+                if config.checks && trap::may_trap(*inst, offset, config.memory_size) {
+                    let site = trap::site(&mut ctx);
+                    ctx.get_label(&label).push_str(site);
+                }
+
+                let new_l = match self.fused_branch(*inst, rest, config) {
+                    Some((if_, test, target)) => {
+                        code = &rest[1..];
+                        let (command, an_label) = branch(&mut ctx, &label, if_, &test, target);
+                        ctx.get_label(&label).push_str(&command);
+                        Some(an_label)
+                    }
+                    None => translate(&label, &mut ctx, offset, *inst)?,
+                };
+
+                if let Some(new_l) = new_l {
                     label = Cow::Owned(new_l);
                 }
             }
+
+            // running off the end of a label stops the program
+            ctx.get_label(&label).push_str(halt());
+        }
+
+        trap::write_traps(output, &mut ctx, handler, uses_stack);
+        let mut removed = ctx.generate(output, &entries);
+
+        threads::write_tick(output, config.threads, budget);
+        if config.threads > 1 {
+            threads::write_spawn(
+                output,
+                &config.memory(),
+                config.threads,
+                config.stack_size(),
+            );
+        }
+        if self.write_hooks(output, &ctx) {
+            pack::add_tick(output, &[FUNC_TICK, FUNC_HOOKS])?;
+        } else {
+            pack::add_tick(output, &[FUNC_TICK])?;
+        }
+
+        removed.labels.extend(
+            self.blocks
+                .keys()
+                .filter(|l| !offsets.contains_key(*l))
+                .map(|l| l.to_string()),
+        );
+        removed.labels.sort();
+
+        let warnings = self
+            .unbounded_loops(&offsets)
+            .into_iter()
+            .map(|l| {
+                format!("the loop through `{l}` runs raw `function` commands, its cost cannot be bounded")
+            })
+            .collect();
+        Ok(Report { removed, warnings })
+    }
+
+    /// A comparison followed by `bi` or `bn` whose result is never read
+    /// again, returns the condition under which the branch is taken.
+    fn fused_branch(
+        &self,
+        inst: Instruction,
+        rest: &[Instruction<'a>],
+        config: &BuildConfig,
+    ) -> Option<(&'static str, String, &'a str)> {
+        if config.opt_level == 0 {
+            return None;
         }
 
-        Ok(ctx.generate(save_as)?)
+        let (if_, test) = condition(inst)?;
+        let (if_, target) = match *rest.first()? {
+            Instruction::BranchIf(target) => (if_, target),
+            Instruction::BranchIfNot(target) => (invert(if_), target),
+            _ => return None,
+        };
+
+        let target_code = &self.blocks.get(target)?.instructions;
+        (self.overwritten_before_read(&rest[1..], Register::R0)
+            && self.overwritten_before_read(target_code, Register::R0))
+        .then_some((if_, test, target))
     }
 }
 
@@ -47,38 +234,199 @@ fn register(reg: Register) -> &'static str {
     }
 }
 
+/// Stop the program, there is nothing to resume on the next tick.
+fn halt() -> String {
+    format!(
+        "scoreboard players set {PREFIX} {PROGRAM_COUNTER} 0\n\
+        scoreboard players set {PREFIX} {HALTED} 1\n"
+    )
+}
+
 fn decode_string(input: &str) -> String {
     input.replace("\\", "")
 }
 
+/// The `execute` test of a comparison, together with the keyword that runs
+/// a command when it is true.
+fn condition(inst: Instruction) -> Option<(&'static str, String)> {
+    match inst {
+        Instruction::Compare(opr) => {
+            let (if_, opr_str) = match opr {
+                CmpOp::Equals => ("if", "="),
+                CmpOp::NotEquals => ("unless", "="),
+                CmpOp::GreaterEq => ("if", ">="),
+                CmpOp::GreaterThan => ("if", ">"),
+                CmpOp::LessEq => ("if", "<="),
+                CmpOp::LessThan => ("if", "<"),
+            };
+
+            Some((
+                if_,
+                format!("score {PREFIX} {REG_R0} {opr_str} {PREFIX} {REG_R1}"),
+            ))
+        }
+
+        Instruction::CompareIn { not, opr: expr } => {
+            let if_ = if not { "unless" } else { "if" };
+
+            let matches = match expr {
+                ExprCmpIn::Value(v) => v.to_string(),
+                ExprCmpIn::Range(lb, ub) => format!(
+                    "{}..{}",
+                    lb.as_ref().map(<_>::to_string).unwrap_or_default(),
+                    ub.as_ref().map(<_>::to_string).unwrap_or_default()
+                ),
+            };
+
+            Some((if_, format!("score {PREFIX} {REG_R0} matches {matches}")))
+        }
+
+        _ => None,
+    }
+}
+
+fn invert(if_: &str) -> &'static str {
+    match if_ {
+        "if" => "unless",
+        _ => "if",
+    }
+}
+
+/// Run `target` if the test passes, otherwise continue in a new anonymous
+/// label, which is returned.
+fn branch(
+    ctx: &mut Context,
+    label: &str,
+    if_: &str,
+    test: &str,
+    target: &str,
+) -> (String, String) {
+    let an_label = ctx.new_anonymous_label();
+
+    let if_true_exec = ctx.enter(label, target);
+    let if_false_exec = ctx.jump(label, &an_label);
+    let else_ = invert(if_);
+
+    // the test may pass again once the target has run, unless it halted
+    let command = format!(
+        "execute {if_} {test} run function {if_true_exec}\n\
+        execute if score {PREFIX} {HALTED} matches 0 {else_} {test} run function {if_false_exec}\n",
+    );
+    (command, an_label)
+}
+
+/// Stop here after setting the timer with the scoreboard subcommand `timer`,
+/// the tick function continues with `resume` once it runs out.
+fn suspend(ctx: &mut Context, label: &str, resume: &str, timer: String) -> String {
+    format!(
+        "scoreboard players {timer}\n\
+        scoreboard players set {PREFIX} {PROGRAM_COUNTER} {}\n\
+        scoreboard players set {PREFIX} {HALTED} 1\n",
+        ctx.dispatch_id(label, resume)
+    )
+}
+
+/// Access memory at `addr` relative to `MEM_OFFSET`. If the offset is known
+/// at compile time, the cell is accessed directly instead of searching for
+/// it with `search_fn`.
+fn memory_access(
+    ctx: &Context,
+    offset: Option<i32>,
+    addr: i32,
+    search_fn: &str,
+    direct: fn(&Memory, usize) -> String,
+) -> String {
+    match direct_cell(offset, addr, ctx.memory_size()) {
+        Some(nth) => direct(ctx.memory(), nth) + "\n",
+        None => format!(
+            "scoreboard players set {PREFIX} {MEM_POINTER} {addr}\n\
+            scoreboard players operation {PREFIX} {MEM_POINTER} += {PREFIX} {MEM_OFFSET}\n\
+            function {search_fn}\n"
+        ),
+    }
+}
+
+/// The memory cell at `addr`, if the offset is known and it is in range.
+fn direct_cell(offset: Option<i32>, addr: i32, memory_size: usize) -> Option<usize> {
+    offset
+        .and_then(|o| o.checked_add(addr))
+        .and_then(|a| usize::try_from(a).ok())
+        .filter(|a| *a < memory_size)
+}
+
+/// With run time checks, continue in a new anonymous label only if the code
+/// so far did not raise a trap.
+fn unless_trapped(ctx: &mut Context, label: &str, switch: &mut Option<String>) -> String {
+    if !ctx.runtime_checks() {
+        return String::new();
+    }
+    let next = switch.insert(ctx.new_anonymous_label());
+    format!(
+        "execute if score {PREFIX} {HALTED} matches 0 run function {}\n",
+        ctx.jump(label, next)
+    )
+}
+
+/// With run time checks, raise a stack overflow unless the frame ending at
+/// `MEM_POINTER` after `top` fits into the memory, before anything is
+/// written to it. Returns the commands and the label that continues only if
+/// it fits.
+fn frame_check(ctx: &mut Context, label: &str, top: String) -> (String, String) {
+    if !ctx.runtime_checks() {
+        return (String::new(), label.to_string());
+    }
+    let next = ctx.new_anonymous_label();
+    let command = format!(
+        "{top}{}\
+        execute if score {PREFIX} {HALTED} matches 0 run function {}\n",
+        trap::stack_check(ctx),
+        ctx.jump(label, &next)
+    );
+    (command, next)
+}
+
 // returns some means switch to a new label
-fn translate<'c>(label: &str, ctx: &'c mut Context, inst: Instruction) -> Result<Option<String>> {
+fn translate(
+    label: &str,
+    ctx: &mut Context,
+    offset: Option<i32>,
+    inst: Instruction,
+) -> Result<Option<String>> {
     let mut switch = None;
 
     let command = match inst {
         Instruction::Branch(b) => {
             // let code generate to an unreachable block
             switch = Some(ctx.new_anonymous_label());
-
-            let label = ctx.get_label(b);
-            let b_id = label.id();
-            let b_fn = label.fn_name();
-
-            format!(
-                "scoreboard players set {PREFIX} {PROGRAM_COUNTER} {b_id}\n\
-                function {b_fn}\n"
-            )
+            format!("function {}\n", ctx.enter(label, b))
         }
 
         Instruction::BranchIf(bi) => {
-            let an_label = switch.insert(ctx.new_anonymous_label());
+            let test = format!("score {PREFIX} {REG_R0} matches 0");
+            let (command, an_label) = branch(ctx, label, "unless", &test, bi);
+            switch = Some(an_label);
+            command
+        }
 
-            let if_true_exec = ctx.get_label(bi).fn_name();
-            let if_false_exec = ctx.get_label(an_label).fn_name();
+        Instruction::BranchIfNot(bn) => {
+            let test = format!("score {PREFIX} {REG_R0} matches 0");
+            let (command, an_label) = branch(ctx, label, "if", &test, bn);
+            switch = Some(an_label);
+            command
+        }
 
+        Instruction::Switch {
+            reg,
+            cases,
+            default,
+        } => {
+            // let code generate to an unreachable block
+            switch = Some(ctx.new_anonymous_label());
+            let cases = cases.iter().map(|case| ctx.enter(label, case)).collect();
+            let default = ctx.enter(label, default);
             format!(
-                "execute unless score {PREFIX} {REG_R0} matches 0 run function {if_true_exec}\n\
-                execute if score {PREFIX} {REG_R0} matches 0 run function {if_false_exec}\n",
+                "function {}\n",
+                ctx.switch(label, register(reg), cases, default)
             )
         }
 
@@ -96,67 +444,185 @@ fn translate<'c>(label: &str, ctx: &'c mut Context, inst: Instruction) -> Result
             format!("scoreboard players operation {PREFIX} {REG_R0} {opr_str} {PREFIX} {REG_R1}\n")
         }
 
+        // the arguments were turned into `store`s by `pass_arguments`
         Instruction::Call {
             mut offset_inc,
-            label,
+            label: callee,
+            ..
         } => {
-            let ret_label = ctx.new_anonymous_label();
-            let ret_block = ctx.get_label(switch.insert(ret_label));
-            let this_id = ctx.get_label(label).id();
+            let top = format!(
+                "scoreboard players operation {PREFIX} {MEM_POINTER} = {PREFIX} {MEM_OFFSET}\n\
+                scoreboard players add {PREFIX} {MEM_POINTER} {}\n",
+                offset_inc
+                    .saturating_add(1)
+                    .saturating_add(ctx.frame_size(callee))
+            );
+            let (check, label) = frame_check(ctx, label, top);
+
+            // the return block restores the offset, `ret` dispatches to it
+            let ret_label = switch.insert(ctx.new_anonymous_label());
+            let ret_id = ctx.dispatch_id(&label, ret_label);
             let ret_pc = offset_inc;
             offset_inc += 1;
 
-            ret_block.push_str(format!(
-                "scoreboard players add {PREFIX} {MEM_OFFSET} -{offset_inc}\n"
+            ctx.get_label(ret_label).push_str(format!(
+                "scoreboard players remove {PREFIX} {MEM_OFFSET} {offset_inc}\n"
             ));
 
-            let function = ctx.get_label(label).fn_name();
-            format!(
-                "scoreboard players set {PREFIX} {REG_R0} {this_id}\n\
-                scoreboard players set {PREFIX} {MEM_POINTER} {ret_pc}\n\
-                function {FUNC_STORE}\n\
+            let function = ctx.enter(&label, callee);
+            let command = format!(
+                "scoreboard players set {PREFIX} {REG_R0} {ret_id}\n\
+                {}\
                 scoreboard players add {PREFIX} {MEM_OFFSET} {offset_inc}\n\
                 function {function}\n",
-            )
+                memory_access(ctx, offset, ret_pc, FUNC_STORE, Memory::store_cell)
+            );
+            ctx.get_label(&label).push_str(command);
+            check
         }
 
-        Instruction::Compare(opr) => {
-            let mut if_ = "if";
+        Instruction::StackCall {
+            label: callee,
+            args,
+        } => {
+            // the old offset and the return block id are stored at the
+            // stack pointer, followed by the frame of the callee
+            let saved = ctx.saved_registers();
+            let frame = saved.len() + 2;
+            let top = format!(
+                "scoreboard players operation {PREFIX} {MEM_POINTER} = {PREFIX} {STACK_POINTER}\n\
+                scoreboard players add {PREFIX} {MEM_POINTER} {}\n",
+                ctx.frame_size(callee).saturating_add(frame as i32)
+            );
+            let (check, label) = frame_check(ctx, label, top);
+            let ret_label = switch.insert(ctx.new_anonymous_label());
+            let ret_id = ctx.dispatch_id(&label, ret_label);
+
+            let mut restore = format!(
+                "scoreboard players operation {PREFIX} {STACK_POINTER} = {PREFIX} {MEM_OFFSET}\n\
+                scoreboard players remove {PREFIX} {STACK_POINTER} 2\n\
+                scoreboard players operation {PREFIX} {MEM_POINTER} = {PREFIX} {STACK_POINTER}\n\
+                function {FUNC_LOAD}\n\
+                scoreboard players operation {PREFIX} {MEM_OFFSET} = {PREFIX} {REG_R0}\n"
+            );
+            restore.extend(saved.iter().rev().map(|reg| stack::pop(*reg)));
+            ctx.get_label(ret_label).push_str(restore);
+
+            let function = ctx.enter(&label, callee);
+            let mut command: String = args
+                .into_iter()
+                .flat_map(Args::iter)
+                .enumerate()
+                .map(|(slot, reg)| stack::store(reg, frame + slot))
+                .collect();
+            command.extend(saved.iter().map(|reg| stack::push(*reg)));
+            command += &format!(
+                "scoreboard players operation {PREFIX} {REG_R0} = {PREFIX} {MEM_OFFSET}\n\
+                scoreboard players operation {PREFIX} {MEM_POINTER} = {PREFIX} {STACK_POINTER}\n\
+                function {FUNC_STORE}\n\
+                scoreboard players set {PREFIX} {REG_R0} {ret_id}\n\
+                scoreboard players add {PREFIX} {MEM_POINTER} 1\n\
+                function {FUNC_STORE}\n\
+                scoreboard players operation {PREFIX} {MEM_OFFSET} = {PREFIX} {STACK_POINTER}\n\
+                scoreboard players add {PREFIX} {MEM_OFFSET} 2\n\
+                scoreboard players operation {PREFIX} {STACK_POINTER} = {PREFIX} {MEM_OFFSET}\n\
+                scoreboard players add {PREFIX} {STACK_POINTER} {}\n\
+                function {function}\n",
+                ctx.frame_size(callee)
+            );
+            ctx.get_label(&label).push_str(command);
+            check
+        }
 
-            let opr_str = match opr {
-                CmpOp::Equals => "=",
-                CmpOp::NotEquals => {
-                    if_ = "unless";
-                    "="
-                }
-                CmpOp::GreaterEq => ">=",
-                CmpOp::GreaterThan => ">",
-                CmpOp::LessEq => "<=",
-                CmpOp::LessThan => "<",
-            };
+        Instruction::Push(reg) => stack::push(reg) + &unless_trapped(ctx, label, &mut switch),
+
+        Instruction::Pop(reg) => stack::pop(reg) + &unless_trapped(ctx, label, &mut switch),
 
-            format!("\
-                execute {if_} score {PREFIX} {REG_R0} {opr_str} {PREFIX} {REG_R1} run scoreboard players set {PREFIX} {REG_R0} 1\n\
-                execute unless score {PREFIX} {REG_R0} matches 1 run scoreboard players set {PREFIX} {REG_R0} 0\n\
-            ")
+        Instruction::LoadPtr(reg) => {
+            format!(
+                "scoreboard players operation {PREFIX} {MEM_POINTER} = {PREFIX} {}\n\
+                function {FUNC_LOAD}\n",
+                register(reg)
+            ) + &unless_trapped(ctx, label, &mut switch)
         }
 
-        Instruction::CompareIn { not, opr: expr } => {
-            let if_ = if not { "unless" } else { "if" };
+        Instruction::StorePtr(reg) => {
+            format!(
+                "scoreboard players operation {PREFIX} {MEM_POINTER} = {PREFIX} {}\n\
+                function {FUNC_STORE}\n",
+                register(reg)
+            ) + &unless_trapped(ctx, label, &mut switch)
+        }
 
-            let matches = match expr {
-                ExprCmpIn::Value(v) => v.to_string(),
-                ExprCmpIn::Range(lb, ub) => format!(
-                    "{}..{}",
-                    lb.as_ref().map(<_>::to_string).unwrap_or_default(),
-                    ub.as_ref().map(<_>::to_string).unwrap_or_default()
-                ),
-            };
+        Instruction::Alloc | Instruction::Free if ctx.heap_size() == 0 => {
+            return Err(anyhow!("`alloc` and `free` need a heap, set `heap_size`"));
+        }
+
+        Instruction::Alloc => format!("function {FUNC_ALLOC}\n"),
+
+        // may raise a trap for a pointer outside of the heap
+        Instruction::Free => {
+            format!("function {FUNC_FREE}\n") + &unless_trapped(ctx, label, &mut switch)
+        }
+
+        Instruction::Bank if !ctx.memory().current_bank => {
+            return Err(anyhow!("`bank` needs a current bank, set `current_bank`"));
+        }
 
-            format!("\
-                execute {if_} score {PREFIX} {REG_R0} matches {matches} run scoreboard players set {PREFIX} {REG_R0} 1\n\
-                execute unless score {PREFIX} {REG_R0} matches 1 run scoreboard players set {PREFIX} {REG_R0} 0\n\
-            ")
+        // may raise a trap for a bank that does not exist
+        Instruction::Bank => {
+            format!("function {FUNC_BANK}\n") + &unless_trapped(ctx, label, &mut switch)
+        }
+
+        Instruction::Addr { dst, label: target } => {
+            let id = ctx.pointer(label, target);
+            format!("scoreboard players set {PREFIX} {} {id}\n", register(dst))
+        }
+
+        Instruction::IndirectCall {
+            mut offset_inc,
+            reg,
+        } => {
+            // the size of the frame is not known, only its start is checked
+            let top = format!(
+                "scoreboard players operation {PREFIX} {MEM_POINTER} = {PREFIX} {MEM_OFFSET}\n\
+                scoreboard players add {PREFIX} {MEM_POINTER} {}\n",
+                offset_inc.saturating_add(1)
+            );
+            let (check, label) = frame_check(ctx, label, top);
+
+            // like `call`, with the id moved out of the way of the return
+            // block id first
+            let ret_label = switch.insert(ctx.new_anonymous_label());
+            let ret_id = ctx.dispatch_id(&label, ret_label);
+            let ret_pc = offset_inc;
+            offset_inc += 1;
+
+            ctx.get_label(ret_label).push_str(format!(
+                "scoreboard players remove {PREFIX} {MEM_OFFSET} {offset_inc}\n"
+            ));
+
+            let function = ctx.indirect_call(&label);
+            let command = format!(
+                "scoreboard players operation {PREFIX} {PROGRAM_COUNTER} = {PREFIX} {}\n\
+                scoreboard players set {PREFIX} {REG_R0} {ret_id}\n\
+                {}\
+                scoreboard players add {PREFIX} {MEM_OFFSET} {offset_inc}\n\
+                function {function}\n",
+                register(reg),
+                memory_access(ctx, offset, ret_pc, FUNC_STORE, Memory::store_cell)
+            );
+            ctx.get_label(&label).push_str(command);
+            check
+        }
+
+        Instruction::Compare(_) | Instruction::CompareIn { .. } => {
+            let (if_, test) = condition(inst).expect("comparison has a condition");
+            format!(
+                "scoreboard players set {PREFIX} {REG_COND} 0\n\
+                execute {if_} {test} run scoreboard players set {PREFIX} {REG_COND} 1\n\
+                scoreboard players operation {PREFIX} {REG_R0} = {PREFIX} {REG_COND}\n"
+            )
         }
 
         Instruction::Move { dst, src } => {
@@ -168,11 +634,11 @@ fn translate<'c>(label: &str, ctx: &'c mut Context, inst: Instruction) -> Result
         }
 
         Instruction::Load { addr } => {
-            format!(
-                "scoreboard players set {PREFIX} {MEM_POINTER} {addr}\n\
-                scoreboard players operation {PREFIX} {MEM_POINTER} += {PREFIX} {MEM_OFFSET}\n\
-                function {FUNC_LOAD}\n"
-            )
+            let mut command = memory_access(ctx, offset, addr, FUNC_LOAD, Memory::load_cell);
+            if direct_cell(offset, addr, ctx.memory_size()).is_none() {
+                command += &unless_trapped(ctx, label, &mut switch);
+            }
+            command
         }
 
         Instruction::Random { dst, min, max } => {
@@ -193,38 +659,124 @@ fn translate<'c>(label: &str, ctx: &'c mut Context, inst: Instruction) -> Result
             format!("scoreboard players set {PREFIX} {dst} {value}\n")
         }
 
+        Instruction::AddConst { dst, value } => {
+            let dst = register(dst);
+            match value.checked_neg() {
+                Some(neg) if value < 0 => {
+                    format!("scoreboard players remove {PREFIX} {dst} {neg}\n")
+                }
+                _ => format!("scoreboard players add {PREFIX} {dst} {value}\n"),
+            }
+        }
+
         Instruction::Store { addr } => {
-            format!(
-                "scoreboard players set {PREFIX} {MEM_POINTER} {addr}\n\
-                scoreboard players operation {PREFIX} {MEM_POINTER} += {PREFIX} {MEM_OFFSET}\n\
-                function {FUNC_STORE}\n"
-            )
+            let mut command = memory_access(ctx, offset, addr, FUNC_STORE, Memory::store_cell);
+            if direct_cell(offset, addr, ctx.memory_size()).is_none() {
+                command += &unless_trapped(ctx, label, &mut switch);
+            }
+            command
         }
 
         Instruction::Yield => {
-            let an_label = ctx.new_anonymous_label();
-            let an_block = ctx.get_label(switch.insert(an_label));
+            let an_label = switch.insert(ctx.new_anonymous_label());
+            suspend(ctx, label, an_label, format!("set {PREFIX} {TIMER} 0"))
+        }
+
+        Instruction::Sleep(delay) => {
+            let an_label = switch.insert(ctx.new_anonymous_label());
+            let timer = match delay {
+                Delay::Ticks(ticks) => format!("set {PREFIX} {TIMER} {ticks}"),
+                Delay::Register(reg) => {
+                    format!("operation {PREFIX} {TIMER} = {PREFIX} {}", register(reg))
+                }
+            };
+            suspend(ctx, label, an_label, timer)
+        }
+
+        Instruction::Spawn(target) => {
+            if ctx.threads() == 1 {
+                return Err(anyhow!("`spawn` needs more than one thread in `threads`"));
+            }
             format!(
-                "scoreboard players set {PREFIX} {PROGRAM_COUNTER} {}",
-                an_block.id()
+                "scoreboard players set {PREFIX} {PROGRAM_COUNTER} {}\n\
+                scoreboard players set {PREFIX} {MEM_POINTER} {}\n\
+                function {FUNC_SPAWN}\n",
+                ctx.dispatch_id(label, target),
+                ctx.frame_size(target)
             )
         }
 
+        Instruction::Join(reg) => {
+            // checks the thread again every tick until it has stopped
+            let wait = ctx.new_anonymous_label();
+            let pause = ctx.new_anonymous_label();
+            let an_label = switch.insert(ctx.new_anonymous_label());
+
+            let reg = register(reg);
+            let mut busy = format!("scoreboard players set {PREFIX} {REG_COND} 0\n");
+            for nth in 1..ctx.threads() {
+                busy += &format!(
+                    "execute if score {PREFIX} {reg} matches {nth} if score {} {PROGRAM_COUNTER} matches 1.. run scoreboard players set {PREFIX} {REG_COND} 1\n",
+                    thread_player(nth)
+                );
+            }
+            let pause_exec = ctx.jump(&wait, &pause).to_string();
+            let continue_exec = ctx.jump(&wait, an_label).to_string();
+            ctx.get_label(&wait).push_str(format!(
+                "{busy}\
+                execute if score {PREFIX} {REG_COND} matches 1 run function {pause_exec}\n\
+                execute if score {PREFIX} {HALTED} matches 0 run function {continue_exec}\n"
+            ));
+            let suspended = suspend(ctx, &pause, &wait, format!("set {PREFIX} {TIMER} 0"));
+            ctx.get_label(&pause).push_str(suspended);
+
+            format!("function {}\n", ctx.jump(label, &wait))
+        }
+
         Instruction::Return => {
             switch = Some(ctx.new_anonymous_label());
-            format!(
-                "scoreboard players set {PREFIX} {MEM_POINTER} -1\n\
-                function {FUNC_LOAD}\n\
-                scoreboard players operation {PROGRAM_COUNTER} = {REG_R0}\n\
-                function {FUNC_EXEC}\n
-                "
-            )
+            if offset == Some(0) {
+                // returning from an entry label, there is no caller
+                halt()
+            } else {
+                // the return block id is stored right before the frame
+                let check = match ctx.budget() {
+                    Some(budget) => format!(
+                        "execute if score {PREFIX} {BUDGET} matches {}.. run scoreboard players set {PREFIX} {HALTED} 1\n\
+                        scoreboard players add {PREFIX} {BUDGET} {}\n",
+                        budget.limit, budget.reserve
+                    ),
+                    None => String::new(),
+                };
+                // a trap has set the program counter already
+                let guard = match direct_cell(offset, -1, ctx.memory_size()) {
+                    None if ctx.runtime_checks() => {
+                        format!("execute if score {PREFIX} {HALTED} matches 0 run ")
+                    }
+                    _ => String::new(),
+                };
+                format!(
+                    "{}\
+                {guard}scoreboard players operation {PREFIX} {PROGRAM_COUNTER} = {PREFIX} {REG_R0}\n\
+                {check}\
+                function {FUNC_EXEC}\n",
+                    memory_access(ctx, offset, -1, FUNC_LOAD, Memory::load_cell)
+                )
+            }
         }
 
         Instruction::Debug { line, info } => {
             format!("say (at: {line}) {}\n", decode_string(info))
         }
 
+        Instruction::DebugDump { line } => {
+            format!(
+                "say (at: {line}) dump\n\
+                scoreboard players operation {PREFIX} {MEM_POINTER} = {PREFIX} {MEM_OFFSET}\n\
+                function {FUNC_DUMP}\n"
+            )
+        }
+
         Instruction::Log(msg) => {
             format!("say {}\n", decode_string(msg))
         }
@@ -233,3 +785,87 @@ fn translate<'c>(label: &str, ctx: &'c mut Context, inst: Instruction) -> Result
     ctx.get_label(label).push_str(&command);
     Ok(switch)
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        bootstrap::{ERROR, OUT_OF_RANGE, PREFIX, PROGRAM_COUNTER, REG_R3},
+        emu,
+        manifest::BuildConfig,
+        mas::VirtualMachine,
+        pack::Output,
+    };
+
+    #[test]
+    fn calls_entries_in_a_frame() {
+        let source = "main:\nset R0 5\nstore 0\ncall 2 helper\nload 0\nmov R3 R0\nret\n\n\
+            helper:\nset R0 7\nstore 0\nret\n";
+        for opt_level in 0..=2 {
+            let config = BuildConfig {
+                entries: vec!["main".to_string(), "helper".to_string()],
+                opt_level,
+                ..Default::default()
+            };
+            let emu = emu::run(source, &config, 10);
+            assert_eq!(emu.reg(ERROR), 0, "-O{opt_level}");
+            assert_eq!(emu.reg(REG_R3), 5, "-O{opt_level}");
+            assert_eq!(emu.cell(&config.memory(), 3), 7, "-O{opt_level}");
+        }
+    }
+
+    #[test]
+    fn jumps_to_entries_on_the_same_stack() {
+        let source = "main:\npush R0\nb main\n";
+        let emu = emu::run(source, &BuildConfig::default(), 10);
+        // pushing past the end of the memory
+        assert_eq!(emu.reg(ERROR), OUT_OF_RANGE.code);
+    }
+
+    #[test]
+    fn loops_through_entries_within_the_budget() {
+        let source = "main:\nload 0\nset R1 1\ncalc +\nstore 0\nb main\n";
+        let config = BuildConfig {
+            command_budget: Some(500),
+            ..Default::default()
+        };
+        let mut emu = emu::Emulator::new(emu::compile(source, &config));
+        emu.function("init");
+        for _ in 0..3 {
+            let start = emu.commands;
+            let count = emu.cell(&config.memory(), 0);
+            match count {
+                0 => emu.function("main"),
+                _ => emu.run_ticks(1),
+            }
+            let commands = emu.commands - start;
+            assert!(commands <= 500, "{commands} commands");
+            assert_ne!(emu.score(PREFIX, PROGRAM_COUNTER), Some(0));
+            assert!(emu.cell(&config.memory(), 0) > count);
+        }
+    }
+
+    #[test]
+    fn rejects_hooks_while_suspended() {
+        let budget = BuildConfig {
+            command_budget: Some(500),
+            ..Default::default()
+        };
+        let default = BuildConfig::default();
+        let cases = [
+            ("main:\nyield\nret\n\n@on_tick count:\nret\n", &default),
+            ("main:\nret\n\n@every(5) count:\nret\n", &budget),
+        ];
+        for (source, config) in cases {
+            let mut vm = VirtualMachine::new();
+            vm.parse_source(source).unwrap();
+            let error = vm.generate(&mut Output::new(), config).unwrap_err();
+            assert!(error.to_string().contains("label `count`"), "{error}");
+        }
+
+        let mut vm = VirtualMachine::new();
+        let source = "main:\nret\n\n@on_tick count:\nret\n";
+        vm.parse_source(source).unwrap();
+        let report = vm.generate(&mut Output::new(), &default);
+        assert!(report.is_ok());
+    }
+}
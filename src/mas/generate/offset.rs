@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+
+use crate::mas::{Instruction, VirtualMachine};
+
+impl<'a> VirtualMachine<'a> {
+    /// `MEM_OFFSET` at the start of every label, if it is the same for every
+    /// way the label can be reached. Entry labels are assumed to be invoked
+    /// with offset 0, labels that are never reached are not known. The
+    /// `handler` of traps runs in a frame set up for it.
+    ///
+    /// The offset stays the same through the whole label, as `call` restores
+    /// it before the code after it runs.
+    pub(super) fn static_offsets(
+        &self,
+        entries: &[String],
+        handler: Option<&'a str>,
+    ) -> HashMap<&'a str, Option<i32>> {
+        let mut offsets = HashMap::new();
+        let mut worklist = Vec::new();
+
+        for entry in entries {
+            if let Some((label, _)) = self.blocks.get_key_value(entry.as_str()) {
+                reach(&mut offsets, &mut worklist, label, Some(0));
+            }
+        }
+        if let Some(handler) = handler {
+            reach(&mut offsets, &mut worklist, handler, None);
+        }
+
+        while let Some(label) = worklist.pop() {
+            let offset = offsets[label];
+
+            for inst in &self.blocks[label].instructions {
+                match *inst {
+                    Instruction::Call {
+                        offset_inc, label, ..
+                    } => {
+                        let callee_offset = offset
+                            .and_then(|o| o.checked_add(offset_inc))
+                            .and_then(|o| o.checked_add(1));
+                        reach(&mut offsets, &mut worklist, label, callee_offset);
+                    }
+                    // may be called through the id from anywhere, threads
+                    // start in their own part of the memory and the stack
+                    // pointer is only known at run time
+                    Instruction::Addr { label, .. }
+                    | Instruction::Spawn(label)
+                    | Instruction::StackCall { label, .. } => {
+                        reach(&mut offsets, &mut worklist, label, None);
+                    }
+                    inst => {
+                        for target in inst.labels() {
+                            reach(&mut offsets, &mut worklist, target, offset);
+                        }
+                    }
+                }
+            }
+        }
+
+        offsets
+    }
+}
+
+fn reach<'a>(
+    offsets: &mut HashMap<&'a str, Option<i32>>,
+    worklist: &mut Vec<&'a str>,
+    label: &'a str,
+    offset: Option<i32>,
+) {
+    match offsets.get(label) {
+        Some(None) => return,
+        Some(known) if *known == offset => return,
+        // reached with different offsets
+        Some(_) => offsets.insert(label, None),
+        None => offsets.insert(label, offset),
+    };
+    worklist.push(label);
+}
@@ -0,0 +1,175 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    bootstrap::{FUNC_LOAD, FUNC_STORE, MEM_POINTER, PREFIX, REG_R0, STACK_POINTER},
+    mas::{Instruction, Register, VirtualMachine},
+};
+
+use super::register;
+
+impl<'a> VirtualMachine<'a> {
+    /// Number of slots in the frame of every label, which `call label`
+    /// reserves before the stack pointer. Labels that branch to each other
+    /// share a frame, whose size is given by the declared parameters or the
+    /// highest slot accessed, whichever is larger.
+    pub(super) fn frame_sizes(&self) -> HashMap<&'a str, i32> {
+        let mut neighbors: HashMap<&'a str, Vec<&'a str>> = HashMap::new();
+        for (&label, function) in &self.blocks {
+            for inst in &function.instructions {
+                if let Instruction::Branch(_)
+                | Instruction::BranchIf(_)
+                | Instruction::BranchIfNot(_)
+                | Instruction::Switch { .. } = inst
+                {
+                    for target in inst.labels() {
+                        neighbors.entry(label).or_default().push(target);
+                        neighbors.entry(target).or_default().push(label);
+                    }
+                }
+            }
+        }
+
+        let mut sizes = HashMap::new();
+        for &label in self.blocks.keys() {
+            if sizes.contains_key(label) {
+                continue;
+            }
+
+            let mut group = vec![label];
+            let mut visited = HashSet::from([label]);
+            let mut size = 0;
+            while let Some(l) = group.pop() {
+                let function = &self.blocks[l];
+                size = size.max(i32::try_from(function.args.len()).unwrap_or(i32::MAX));
+                for inst in &function.instructions {
+                    if let Instruction::Load { addr } | Instruction::Store { addr } = *inst {
+                        size = size.max(addr.saturating_add(1));
+                    }
+                }
+                for &n in neighbors.get(l).into_iter().flatten() {
+                    if visited.insert(n) {
+                        group.push(n);
+                    }
+                }
+            }
+
+            for l in visited {
+                sizes.insert(l, size);
+            }
+        }
+        sizes
+    }
+
+    /// Check that labels using the stack are only called with `call label`,
+    /// as `call n label` and `icall` do not move the stack pointer above
+    /// the new frame.
+    pub(super) fn check_stack_frames(&self, offsets: &HashMap<&'a str, Option<i32>>) -> Result<()> {
+        for (label, function) in &self.blocks {
+            if !offsets.contains_key(label) {
+                continue;
+            }
+            for inst in &function.instructions {
+                let callee = match *inst {
+                    Instruction::Call { label, .. } | Instruction::Addr { label, .. } => label,
+                    _ => continue,
+                };
+                if let Some(user) = self.stack_user(callee) {
+                    return Err(anyhow!(
+                        "`{callee}` is called by `{label}` with an offset, but `{user}` uses the stack, call it with `call {callee}`"
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// A label that uses the stack within the frame of `label`.
+    fn stack_user(&self, label: &'a str) -> Option<&'a str> {
+        let mut visited = vec![label];
+        let mut worklist = vec![label];
+
+        while let Some(l) = worklist.pop() {
+            for inst in &self.blocks.get(l)?.instructions {
+                match *inst {
+                    Instruction::Push(_) | Instruction::Pop(_) | Instruction::StackCall { .. } => {
+                        return Some(l)
+                    }
+                    Instruction::Branch(_)
+                    | Instruction::BranchIf(_)
+                    | Instruction::BranchIfNot(_)
+                    | Instruction::Switch { .. } => {
+                        for target in inst.labels() {
+                            if !visited.contains(&target) {
+                                visited.push(target);
+                                worklist.push(target);
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        None
+    }
+
+    /// Whether any reachable label uses the stack.
+    pub(super) fn uses_stack(&self, offsets: &HashMap<&'a str, Option<i32>>) -> bool {
+        offsets.keys().any(|l| {
+            self.blocks[l].instructions.iter().any(|inst| {
+                matches!(
+                    inst,
+                    Instruction::Push(_) | Instruction::Pop(_) | Instruction::StackCall { .. }
+                )
+            })
+        })
+    }
+}
+
+/// Store `reg` at the stack pointer and move it up.
+pub(super) fn push(reg: Register) -> String {
+    // `FUNC_STORE` stores R0, so the register takes its place for a moment
+    let swap = swap_r0(reg);
+    format!(
+        "{swap}\
+        scoreboard players operation {PREFIX} {MEM_POINTER} = {PREFIX} {STACK_POINTER}\n\
+        function {FUNC_STORE}\n\
+        {swap}\
+        scoreboard players add {PREFIX} {STACK_POINTER} 1\n"
+    )
+}
+
+/// Store `reg` at `slot` above the stack pointer, which stays put.
+pub(super) fn store(reg: Register, slot: usize) -> String {
+    let swap = swap_r0(reg);
+    format!(
+        "scoreboard players operation {PREFIX} {MEM_POINTER} = {PREFIX} {STACK_POINTER}\n\
+        scoreboard players add {PREFIX} {MEM_POINTER} {slot}\n\
+        {swap}\
+        function {FUNC_STORE}\n\
+        {swap}"
+    )
+}
+
+/// Move the stack pointer down and load the value there into `reg`.
+pub(super) fn pop(reg: Register) -> String {
+    let swap = swap_r0(reg);
+    format!(
+        "scoreboard players remove {PREFIX} {STACK_POINTER} 1\n\
+        scoreboard players operation {PREFIX} {MEM_POINTER} = {PREFIX} {STACK_POINTER}\n\
+        {swap}\
+        function {FUNC_LOAD}\n\
+        {swap}"
+    )
+}
+
+fn swap_r0(reg: Register) -> String {
+    match reg {
+        Register::R0 => String::new(),
+        reg => format!(
+            "scoreboard players operation {PREFIX} {REG_R0} >< {PREFIX} {}\n",
+            register(reg)
+        ),
+    }
+}
@@ -0,0 +1,164 @@
+use crate::{
+    bootstrap::{
+        thread_player, Memory, BUDGET, CURRENT_THREAD, FUNC_EXEC, FUNC_SPAWN, FUNC_TICK, HALTED,
+        MEM_OFFSET, MEM_POINTER, PREFIX, PROGRAM_COUNTER, REG_COND, REG_R0, STACK_POINTER,
+        THREAD_STATE, TIMER, TURN,
+    },
+    pack::Output,
+};
+
+use super::budget::Budget;
+
+/// Commands the scheduler runs to swap a thread in and out, on top of
+/// resuming it.
+pub(super) const SWITCH_COST: usize = 16;
+
+/// Write the tick function, which resumes the program once it has slept
+/// long enough. With more than one thread, it resumes every thread in turn,
+/// starting with a different one every tick.
+pub(super) fn write_tick(output: &mut Output, threads: usize, budget: Option<Budget>) {
+    let resume = format!(
+        "execute if score {PREFIX} {PROGRAM_COUNTER} matches 1.. if score {PREFIX} {TIMER} matches ..0 run scoreboard players set {PREFIX} {HALTED} 0\n\
+        execute if score {PREFIX} {HALTED} matches 0 run function {FUNC_EXEC}\n"
+    );
+    let timer = |player: &str| {
+        format!(
+            "execute if score {player} {TIMER} matches 1.. run scoreboard players remove {player} {TIMER} 1\n"
+        )
+    };
+    let charge = |commands: usize| match budget {
+        Some(_) => format!("scoreboard players set {PREFIX} {BUDGET} {commands}\n"),
+        None => String::new(),
+    };
+
+    if threads == 1 {
+        let reserve = budget.map_or(0, |b| b.reserve);
+        output.add_function(
+            FUNC_TICK,
+            format!("{}{}{resume}", timer(PREFIX), charge(reserve)),
+        );
+        return;
+    }
+
+    // every thread is charged on its own, the tick only for the commands
+    // that decide which ones run
+    let mut content = charge(4 * threads + 5);
+    content += &timer(PREFIX);
+    for nth in 1..threads {
+        content += &timer(&thread_player(nth));
+    }
+    content += &format!(
+        "scoreboard players add {PREFIX} {TURN} 1\n\
+        execute if score {PREFIX} {TURN} matches {threads}.. run scoreboard players set {PREFIX} {TURN} 0\n"
+    );
+
+    let (add, affordable) = match budget {
+        Some(budget) => (
+            format!(
+                "scoreboard players add {PREFIX} {BUDGET} {}\n",
+                budget.reserve
+            ),
+            format!("if score {PREFIX} {BUDGET} matches ..{} ", budget.limit - 1),
+        ),
+        None => (String::new(), String::new()),
+    };
+
+    for first in 0..threads {
+        let turn = format!("{FUNC_TICK}/Turn{first}");
+        content +=
+            &format!("execute if score {PREFIX} {TURN} matches {first} run function {turn}\n");
+
+        let mut turn_content = String::new();
+        for nth in (first..threads).chain(0..first) {
+            let started = match nth {
+                // the entry thread checks on its own
+                0 => String::new(),
+                _ => format!(
+                    "if score {} {PROGRAM_COUNTER} matches 1.. ",
+                    thread_player(nth)
+                ),
+            };
+            turn_content +=
+                &format!("execute {started}{affordable}run function {FUNC_TICK}/Thread{nth}\n");
+        }
+        output.add_function(&turn, turn_content);
+    }
+    output.add_function(FUNC_TICK, content);
+
+    output.add_function(&format!("{FUNC_TICK}/Thread0"), format!("{add}{resume}"));
+    for nth in 1..threads {
+        let swap = swap_state(nth);
+        output.add_function(
+            &format!("{FUNC_TICK}/Thread{nth}"),
+            format!(
+                "{add}{swap}\
+                scoreboard players set {PREFIX} {CURRENT_THREAD} {nth}\n\
+                {resume}\
+                {swap}\
+                scoreboard players set {PREFIX} {CURRENT_THREAD} 0\n"
+            ),
+        );
+    }
+}
+
+/// Exchange the state of the running thread with the state saved for the
+/// `nth` thread.
+fn swap_state(nth: usize) -> String {
+    let player = thread_player(nth);
+    THREAD_STATE
+        .iter()
+        .map(|objective| {
+            format!("scoreboard players operation {PREFIX} {objective} >< {player} {objective}\n")
+        })
+        .collect()
+}
+
+/// Write `FUNC_SPAWN`, which starts the block whose id is in
+/// `PROGRAM_COUNTER` in a thread that is not running, with a copy of the
+/// registers and the frame size of the block in `MEM_POINTER`. The index
+/// of the thread, or 0 if every thread is busy, is written into `R0`.
+///
+/// Every thread but the entry thread gets its own part of the memory, with
+/// a return slot of 0 in front of its frame, so that `ret` stops it.
+pub(super) fn write_spawn(output: &mut Output, memory: &Memory, threads: usize, stack_size: usize) {
+    let stride = stack_size / threads;
+    let mut content = format!("scoreboard players set {PREFIX} {REG_COND} 0\n");
+
+    for nth in 1..threads {
+        let player = thread_player(nth);
+        let spawn = format!("{FUNC_SPAWN}/Thread{nth}");
+        content += &format!(
+            "execute if score {PREFIX} {REG_COND} matches 0 unless score {PREFIX} {CURRENT_THREAD} matches {nth} unless score {player} {PROGRAM_COUNTER} matches 1.. run function {spawn}\n"
+        );
+
+        let base = nth * stride;
+        let mut thread = String::new();
+        for objective in THREAD_STATE {
+            thread += &match objective {
+                TIMER => format!("scoreboard players set {player} {TIMER} 0\n"),
+                MEM_OFFSET => format!(
+                    "scoreboard players set {player} {MEM_OFFSET} {}\n",
+                    base + 1
+                ),
+                STACK_POINTER => format!(
+                    "scoreboard players operation {player} {STACK_POINTER} = {PREFIX} {MEM_POINTER}\n\
+                    scoreboard players add {player} {STACK_POINTER} {}\n",
+                    base + 1
+                ),
+                _ => format!(
+                    "scoreboard players operation {player} {objective} = {PREFIX} {objective}\n"
+                ),
+            };
+        }
+        thread += &format!(
+            "scoreboard players set {PREFIX} {REG_R0} 0\n\
+            {}\n\
+            scoreboard players set {PREFIX} {REG_COND} {nth}\n",
+            memory.store_cell(base)
+        );
+        output.add_function(&spawn, thread);
+    }
+
+    content += &format!("scoreboard players operation {PREFIX} {REG_R0} = {PREFIX} {REG_COND}\n");
+    output.add_function(FUNC_SPAWN, content);
+}
@@ -0,0 +1,230 @@
+use anyhow::{anyhow, Result};
+
+use crate::{
+    bootstrap::{
+        BAD_PC, CURRENT_THREAD, ERROR, FAULT_ADDR, FAULT_PC, FUNC_TRAP, HALTED, MEM_OFFSET,
+        MEM_POINTER, PREFIX, PROGRAM_COUNTER, REG_COND, REG_R0, STACK_OVERFLOW, STACK_POINTER,
+        TIMER, TRAPS,
+    },
+    mas::{Hook, Instruction, VirtualMachine},
+    pack::Output,
+};
+
+use super::{budget::CHECK_COST, ctx::Context, direct_cell, halt};
+
+impl<'a> VirtualMachine<'a> {
+    /// The label bound to `@on_trap`, which must not start the program in
+    /// any other way.
+    pub(super) fn trap_handler(&self, entries: &[String]) -> Result<Option<&'a str>> {
+        let mut handlers = self
+            .blocks
+            .values()
+            .filter(|f| f.hooks.contains(&Hook::Trap))
+            .map(|f| f.name);
+
+        let Some(handler) = handlers.next() else {
+            return Ok(None);
+        };
+        if let Some(other) = handlers.next() {
+            return Err(anyhow!(
+                "only one label can be bound to `@on_trap`, found `{handler}` and `{other}`"
+            ));
+        }
+        if entries.iter().any(|e| e == handler) {
+            return Err(anyhow!(
+                "`@on_trap` label `{handler}` cannot also be an entry"
+            ));
+        }
+        Ok(Some(handler))
+    }
+}
+
+/// Commands run on the tick after a trap before the handler starts, where
+/// storing into a known cell runs `direct` commands.
+pub(super) fn setup_cost(threads: usize, direct: usize) -> usize {
+    (3 + direct) * threads + 6 + CHECK_COST
+}
+
+/// Whether the code of `inst` may raise a trap with run time checks.
+pub(super) fn may_trap(inst: Instruction, offset: Option<i32>, memory_size: usize) -> bool {
+    match inst {
+        Instruction::Load { addr } | Instruction::Store { addr } => {
+            direct_cell(offset, addr, memory_size).is_none()
+        }
+        // loads the return block id and dispatches to it
+        Instruction::Return => offset != Some(0),
+        Instruction::Call { .. }
+        | Instruction::StackCall { .. }
+        | Instruction::IndirectCall { .. }
+        | Instruction::Push(_)
+        | Instruction::Pop(_)
+        | Instruction::LoadPtr(_)
+        | Instruction::StorePtr(_)
+        | Instruction::Free
+        | Instruction::Bank => true,
+        _ => false,
+    }
+}
+
+/// Record the number of the instruction that follows in `FAULT_PC`, unless
+/// an error was recorded before.
+pub(super) fn site(ctx: &mut Context) -> String {
+    format!(
+        "execute if score {PREFIX} {ERROR} matches 0 run scoreboard players set {PREFIX} {FAULT_PC} {}\n",
+        ctx.trap_site()
+    )
+}
+
+/// Check that the frame ending at `MEM_POINTER` lies in the memory of the
+/// running thread, or raise `STACK_OVERFLOW`.
+pub(super) fn stack_check(ctx: &Context) -> String {
+    let stride = ctx.stride();
+    if ctx.threads() == 1 {
+        return format!(
+            "execute unless score {PREFIX} {MEM_POINTER} matches 0..{stride} run {}\n",
+            STACK_OVERFLOW.raise()
+        );
+    }
+
+    (0..ctx.threads())
+        .map(|nth| {
+            format!(
+                "execute if score {PREFIX} {CURRENT_THREAD} matches {nth} unless score {PREFIX} {MEM_POINTER} matches {}..{} run {}\n",
+                nth * stride,
+                (nth + 1) * stride,
+                STACK_OVERFLOW.raise()
+            )
+        })
+        .collect()
+}
+
+/// Write `FUNC_TRAP` and the function raising every trap. A trap records
+/// the error and stops the program. If no error was recorded before, the
+/// thread resumes with `handler` on the next tick, with the error code in
+/// `R0` and a frame at the start of its memory whose return slot stops it.
+pub(super) fn write_traps(
+    output: &mut Output,
+    ctx: &mut Context,
+    handler: Option<&str>,
+    uses_stack: bool,
+) {
+    // only the first error is recorded until it is reset
+    let first = format!("execute if score {PREFIX} {ERROR} matches 0 run");
+
+    for trap in TRAPS {
+        // an invalid program counter is not raised by an instruction
+        let site = match trap.code == BAD_PC.code {
+            true => format!("{first} scoreboard players set {PREFIX} {FAULT_PC} 0\n"),
+            false => String::new(),
+        };
+        // the commands left in the faulting code may raise more traps
+        output.add_function(
+            trap.function,
+            format!(
+                "execute if score {PREFIX} {HALTED} matches 0 run say mcvm fatal error: {}\n\
+                scoreboard players set {PREFIX} {REG_COND} {}\n\
+                {site}\
+                execute if score {PREFIX} {HALTED} matches 0 run function {FUNC_TRAP}\n",
+                trap.message, trap.code
+            ),
+        );
+    }
+
+    let mut content = format!(
+        "{first} scoreboard players operation {PREFIX} {FAULT_ADDR} = {PREFIX} {MEM_POINTER}\n\
+        {}",
+        halt()
+    );
+
+    if let Some(handler) = handler {
+        // the frame is set up once the faulting code has finished
+        let setup = ctx.new_anonymous_label();
+        let stride = ctx.stride();
+        let mut frame = String::new();
+        for nth in 0..ctx.threads() {
+            let only = match nth {
+                0 if ctx.threads() == 1 => String::new(),
+                _ => format!("execute if score {PREFIX} {CURRENT_THREAD} matches {nth} run "),
+            };
+            frame += &format!(
+                "{only}scoreboard players set {PREFIX} {MEM_OFFSET} {}\n",
+                nth * stride + 1
+            );
+            for line in ctx.memory().store_cell(nth * stride).lines() {
+                frame += &format!("{only}{line}\n");
+            }
+        }
+        if uses_stack {
+            frame += &format!(
+                "scoreboard players operation {PREFIX} {STACK_POINTER} = {PREFIX} {MEM_OFFSET}\n\
+                scoreboard players add {PREFIX} {STACK_POINTER} {}\n",
+                ctx.frame_size(handler)
+            );
+        }
+        let function = ctx.enter(&setup, handler);
+        ctx.get_label(&setup).push_str(format!(
+            "scoreboard players set {PREFIX} {REG_R0} 0\n\
+            {frame}\
+            scoreboard players operation {PREFIX} {REG_R0} = {PREFIX} {ERROR}\n\
+            function {function}\n"
+        ));
+
+        content += &format!(
+            "{first} scoreboard players set {PREFIX} {PROGRAM_COUNTER} {}\n\
+            {first} scoreboard players set {PREFIX} {TIMER} 0\n",
+            ctx.root_id(&setup)
+        );
+    }
+
+    content +=
+        &format!("{first} scoreboard players operation {PREFIX} {ERROR} = {PREFIX} {REG_COND}\n");
+    output.add_function(FUNC_TRAP, content);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        bootstrap::{ERROR, FAULT_PC, FUNC_INIT, MEM_OFFSET, PREFIX},
+        emu::{self, Emulator},
+        manifest::BuildConfig,
+    };
+
+    #[test]
+    fn records_the_faulting_instruction() {
+        // the second `loadp` is the second instruction that may trap
+        let source = "main:\nset R1 1\nloadp R1\nset R1 -5\nloadp R1\nret\n";
+        let config = BuildConfig {
+            opt_level: 0,
+            ..Default::default()
+        };
+        let emu = emu::run(source, &config, 10);
+        assert_eq!(emu.reg(ERROR), 1);
+        assert_eq!(emu.reg(FAULT_PC), 2);
+    }
+
+    #[test]
+    fn entries_start_at_offset_0() {
+        // `foo` is called from two frames, so it accesses memory through
+        // the offset
+        let source = "main:\ncall 0 foo\ncall 3 foo\nret\n\n\
+            foo:\nset R0 1\nstore 0\nret\n";
+        for checks in [false, true] {
+            let config = BuildConfig {
+                opt_level: 0,
+                checks,
+                ..Default::default()
+            };
+            let mut emu = Emulator::new(emu::compile(source, &config));
+            emu.function(FUNC_INIT);
+            // as if a stopped program had left its frame
+            emu.set(PREFIX, MEM_OFFSET, 5);
+            emu.function("main");
+            emu.run_ticks(10);
+
+            let memory = config.memory();
+            let cells: Vec<_> = (0..8).map(|nth| emu.cell(&memory, nth)).collect();
+            assert_eq!(cells, [1, 1, 0, 2, 1, 0, 0, 0], "checks {checks}");
+            assert_eq!(emu.reg(ERROR), 0, "checks {checks}");
+        }
+    }
+}
@@ -0,0 +1,329 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::manifest::BuildConfig;
+
+use super::{Instruction, VirtualMachine};
+
+impl<'a> VirtualMachine<'a> {
+    /// Replace `b` and `call` of small labels, or labels referenced only
+    /// once, by their instructions. A `call` right before `ret` becomes a
+    /// jump that moves the arguments into the current frame.
+    pub(super) fn inline(&mut self, config: &BuildConfig) {
+        let mut references = HashMap::new();
+        for function in self.blocks.values() {
+            for label in function.instructions.iter().flat_map(Instruction::labels) {
+                *references.entry(label).or_insert(0) += 1;
+            }
+        }
+
+        let at_base = self.labels_at_base(config);
+        let labels: Vec<&'a str> = self.blocks.keys().copied().collect();
+        for label in labels {
+            let code = self.inline_label(label, &references, config);
+            let code = if config.tail_calls && !at_base.contains(label) {
+                self.tail_calls(code)
+            } else {
+                code
+            };
+            self.blocks.get_mut(label).unwrap().instructions = code;
+        }
+    }
+
+    /// Labels that may run in a frame at offset 0, which has no return slot
+    /// in front of it for a tail call to keep: the entries, the labels bound
+    /// to hooks, and every label they jump to.
+    fn labels_at_base(&self, config: &BuildConfig) -> HashSet<&'a str> {
+        let entries = &config.entries;
+        let mut worklist: Vec<&'a str> = self
+            .blocks
+            .values()
+            .filter(|f| !f.hooks.is_empty() || entries.iter().any(|e| e == f.name))
+            .map(|f| f.name)
+            .collect();
+
+        let mut at_base = HashSet::new();
+        while let Some(label) = worklist.pop() {
+            let Some(function) = self.blocks.get(label) else {
+                continue;
+            };
+            if !at_base.insert(function.name) {
+                continue;
+            }
+            for inst in &function.instructions {
+                // jumps keep the frame, calls start a new one
+                if let Instruction::Branch(_)
+                | Instruction::BranchIf(_)
+                | Instruction::BranchIfNot(_)
+                | Instruction::Switch { .. } = inst
+                {
+                    worklist.extend(inst.labels());
+                }
+            }
+        }
+        at_base
+    }
+
+    fn inlinable(
+        &self,
+        label: &str,
+        references: &HashMap<&str, i32>,
+        config: &BuildConfig,
+    ) -> bool {
+        let Some(function) = self.blocks.get(label) else {
+            return false;
+        };
+
+        function.instructions.len() <= config.inline_size
+            || config.inline_single_caller && references.get(label) == Some(&1)
+    }
+
+    fn inline_label(
+        &self,
+        label: &'a str,
+        references: &HashMap<&str, i32>,
+        config: &BuildConfig,
+    ) -> Vec<Instruction<'a>> {
+        let mut code = Vec::new();
+        // labels whose code is being copied, to not inline loops forever
+        let mut chain = vec![label];
+        let mut insts = self.blocks[label].instructions.as_slice();
+
+        'label: loop {
+            for inst in insts {
+                match *inst {
+                    // the code after `b` is unreachable
+                    Instruction::Branch(target)
+                        if !chain.contains(&target)
+                            && self.inlinable(target, references, config) =>
+                    {
+                        chain.push(target);
+                        insts = &self.blocks[target].instructions;
+                        continue 'label;
+                    }
+                    Instruction::Call {
+                        offset_inc, label, ..
+                    } if self.inlinable(label, references, config) => {
+                        match self.call_body(label, offset_inc) {
+                            Some(body) => code.extend(body),
+                            None => code.push(*inst),
+                        }
+                    }
+                    inst => code.push(inst),
+                }
+            }
+
+            return code;
+        }
+    }
+
+    /// Instructions of a `call`ed label that only runs straight-line code up
+    /// to its `ret`, with the addresses moved into its frame.
+    fn call_body(&self, label: &str, offset_inc: i32) -> Option<Vec<Instruction<'a>>> {
+        let frame = offset_inc.checked_add(1)?;
+        let mut body = Vec::new();
+
+        for inst in &self.blocks[label].instructions {
+            body.push(match *inst {
+                Instruction::Return => return Some(body),
+                Instruction::Load { addr } => Instruction::Load {
+                    addr: addr.checked_add(frame)?,
+                },
+                Instruction::Store { addr } => Instruction::Store {
+                    addr: addr.checked_add(frame)?,
+                },
+                Instruction::Branch(_)
+                | Instruction::BranchIf(_)
+                | Instruction::BranchIfNot(_)
+                | Instruction::Switch { .. }
+                | Instruction::Call { .. }
+                | Instruction::StackCall { .. }
+                | Instruction::IndirectCall { .. }
+                | Instruction::Yield
+                | Instruction::Sleep(_)
+                | Instruction::Join(_)
+                // read the offset or the stack pointer, which differ in the
+                // caller's frame
+                | Instruction::Push(_)
+                | Instruction::Pop(_)
+                | Instruction::DebugDump { .. }
+                // raw commands may look at the offset
+                | Instruction::RawCommand(_) => return None,
+                inst => inst,
+            });
+        }
+
+        // stops the program instead of returning
+        None
+    }
+
+    /// Turn `call n label; ret` into copying the frame of `label` down to
+    /// the current frame, followed by `b label`. The callee then returns to
+    /// the caller of the current label directly, so the current frame must
+    /// have been entered by a call.
+    fn tail_calls(&self, mut code: Vec<Instruction<'a>>) -> Vec<Instruction<'a>> {
+        let mut i = 0;
+
+        while i + 1 < code.len() {
+            let (
+                Instruction::Call {
+                    offset_inc, label, ..
+                },
+                Instruction::Return,
+            ) = (code[i], code[i + 1])
+            else {
+                i += 1;
+                continue;
+            };
+
+            let Some(size) = self.frame_size(label) else {
+                i += 1;
+                continue;
+            };
+
+            let mut jump = Vec::new();
+            for slot in 0..size {
+                jump.push(Instruction::Load {
+                    addr: offset_inc + 1 + slot,
+                });
+                jump.push(Instruction::Store { addr: slot });
+            }
+            jump.push(Instruction::Branch(label));
+
+            let len = jump.len();
+            code.splice(i..i + 2, jump);
+            i += len;
+        }
+
+        code
+    }
+
+    /// Number of slots at the start of the frame of `label` that hold its
+    /// arguments. Those are the declared parameters if there are any,
+    /// otherwise every slot the label may access, as long as it does not
+    /// call other labels that could read further.
+    fn frame_size(&self, label: &str) -> Option<i32> {
+        let function = self.blocks.get(label)?;
+        if !function.args.is_empty() {
+            return i32::try_from(function.args.len()).ok();
+        }
+
+        let mut size = 0;
+        let mut visited = vec![function.name];
+        let mut worklist = vec![function];
+
+        while let Some(function) = worklist.pop() {
+            for inst in &function.instructions {
+                match *inst {
+                    // -1 is the return slot, which the jump keeps
+                    Instruction::Load { addr } | Instruction::Store { addr } if addr >= -1 => {
+                        size = size.max(addr + 1);
+                    }
+                    Instruction::Load { .. }
+                    | Instruction::Store { .. }
+                    | Instruction::Call { .. }
+                    | Instruction::StackCall { .. }
+                    | Instruction::IndirectCall { .. }
+                    // the stack starts above the frame of the label
+                    | Instruction::Push(_)
+                    | Instruction::Pop(_)
+                    | Instruction::RawCommand(_) => return None,
+                    Instruction::Branch(_)
+                    | Instruction::BranchIf(_)
+                    | Instruction::BranchIfNot(_)
+                    | Instruction::Switch { .. } => {
+                        for target in inst.labels() {
+                            let target = self.blocks.get(target)?;
+                            if !visited.contains(&target.name) {
+                                visited.push(target.name);
+                                worklist.push(target);
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        Some(size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        bootstrap::{ERROR, PROGRAM_COUNTER},
+        emu,
+        manifest::BuildConfig,
+        mas::{Instruction, VirtualMachine},
+    };
+
+    #[test]
+    fn keeps_calls_from_entries() {
+        // `foo` is called from two frames and branches, so it is not inlined
+        let source = "main:\nset R0 5\nstore 1\ncall 0 foo\nret\n\n\
+            foo:\nload 0\ncmpin 5\nbn done\nload 0\nset R1 2\ncalc *\nstore 0\nret\n\n\
+            done:\nret\n\n\
+            other:\ncall 4 foo\nlog \"x\"\n";
+        for checks in [true, false] {
+            let config = BuildConfig {
+                opt_level: 2,
+                checks,
+                ..Default::default()
+            };
+            let emu = emu::run(source, &config, 10);
+            assert_eq!(emu.cell(&config.memory(), 1), 10, "checks {checks}");
+            assert_eq!(emu.reg(ERROR), 0, "checks {checks}");
+            assert_eq!(emu.reg(PROGRAM_COUNTER), 0, "checks {checks}");
+            assert!(emu.said.is_empty(), "checks {checks}: {:?}", emu.said);
+        }
+    }
+
+    #[test]
+    fn jumps_from_called_labels() {
+        let source = "main:\nset R0 3\nstore 1\ncall 0 outer\nload 1\nstore 5\nret\n\n\
+            outer:\nload 0\nset R1 1\ncalc +\nstore 2\ncall 1 foo\nret\n\n\
+            foo:\nload 0\ncmpin 0\nbi zero\nload 0\nset R1 2\ncalc *\nstore 0\nret\n\n\
+            zero:\nret\n";
+        let config = BuildConfig {
+            opt_level: 2,
+            ..Default::default()
+        };
+        let mut vm = VirtualMachine::new();
+        vm.parse_source(source).unwrap();
+        vm.optimize(&config);
+        let outer = &vm.blocks["outer"].instructions;
+        assert!(
+            matches!(outer.last(), Some(Instruction::Branch("foo"))),
+            "{outer:?}"
+        );
+
+        // the result of `foo` is left in the frame of `outer`
+        let emu = emu::run(source, &config, 10);
+        assert_eq!(emu.cell(&config.memory(), 5), 8);
+        assert_eq!(emu.reg(ERROR), 0);
+    }
+
+    #[test]
+    fn keeps_calls_that_look_at_the_frame() {
+        let source = "main:\nset R0 4\nstore 3\ncall 2 show\nret\n\nshow:\ndebug dump\nret\n";
+        let mut said = None;
+        for opt_level in [0, 2] {
+            let config = BuildConfig {
+                opt_level,
+                ..Default::default()
+            };
+            let mut vm = VirtualMachine::new();
+            vm.parse_source(source).unwrap();
+            vm.optimize(&config);
+            let calls = vm.blocks["main"]
+                .instructions
+                .iter()
+                .filter(|inst| matches!(inst, Instruction::Call { .. }))
+                .count();
+            assert_eq!(calls, 1, "-O{opt_level}");
+
+            let emu = emu::run(source, &config, 10);
+            assert_eq!(&emu.said, said.get_or_insert_with(|| emu.said.clone()));
+        }
+    }
+}
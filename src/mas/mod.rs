@@ -1,21 +1,60 @@
-use std::collections::HashMap;
+use std::{
+    collections::BTreeMap,
+    fmt::{Display, Formatter},
+};
 
+use serde::Deserialize;
+
+pub use fmt::format_source;
+pub use parse::{parse_line, FuncOrInst};
+
+mod args;
+mod cst;
+mod fmt;
 mod generate;
+mod inline;
+mod optimize;
 mod parse;
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct VirtualMachine<'a> {
-    blocks: HashMap<&'a str, Function<'a>>,
+    // ordered, so that every build assigns the same ids to the same labels
+    blocks: BTreeMap<&'a str, Function<'a>>,
 }
 
 #[derive(Debug)]
 pub struct Function<'a> {
     pub name: &'a str,
     pub args: Vec<&'a str>,
+    /// Events that run the label on their own, written before its name.
+    pub hooks: Vec<Hook>,
     pub instructions: Vec<Instruction<'a>>,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Hook {
+    /// `@on_load`, once the pack is loaded.
+    Load,
+    /// `@on_tick`, every tick.
+    Tick,
+    /// `@every(n)`, every `n` ticks.
+    Every(u32),
+    /// `@on_trap`, on the next tick after a runtime error.
+    Trap,
+}
+
+impl Display for Hook {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Hook::Load => write!(f, "@on_load"),
+            Hook::Tick => write!(f, "@on_tick"),
+            Hook::Every(ticks) => write!(f, "@every({ticks})"),
+            Hook::Trap => write!(f, "@on_trap"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
 pub enum Register {
     R0,
     R1,
@@ -50,22 +89,122 @@ pub enum ExprCmpIn {
     Range(Option<i32>, Option<i32>),
 }
 
+/// Labels of a `switch`, as written between the brackets.
+#[derive(Clone, Copy, Debug)]
+pub struct Cases<'a>(&'a str);
+
+impl<'a> Cases<'a> {
+    pub fn iter(self) -> impl Iterator<Item = &'a str> {
+        self.0.split(',').map(str::trim).filter(|l| !l.is_empty())
+    }
+}
+
+/// Registers passed to a label, as written between the parentheses.
+#[derive(Clone, Copy, Debug)]
+pub struct Args<'a>(&'a str);
+
+impl<'a> Args<'a> {
+    pub fn iter(self) -> impl Iterator<Item = Register> + 'a {
+        self.0
+            .split(',')
+            .map(str::trim)
+            .filter(|r| !r.is_empty())
+            .map(|r| match r {
+                "R0" => Register::R0,
+                "R1" => Register::R1,
+                "R2" => Register::R2,
+                "R3" => Register::R3,
+                // `arguments` only accepts registers
+                _ => unreachable!("unknown register `{r}`"),
+            })
+    }
+}
+
+/// Number of ticks to sleep for.
+#[derive(Clone, Copy, Debug)]
+pub enum Delay {
+    Ticks(i32),
+    Register(Register),
+}
+
 #[derive(Clone, Copy, Debug)]
 pub enum Instruction<'a> {
     RawCommand(&'a str),
     Move { dst: Register, src: Register },
     Set { dst: Register, value: i32 },
+    /// Only produced by the optimizer.
+    AddConst { dst: Register, value: i32 },
     Load { addr: i32 },
     Store { addr: i32 },
     Compare(CmpOp),
     CompareIn { not: bool, opr: ExprCmpIn },
     Branch(&'a str),
     BranchIf(&'a str),
+    BranchIfNot(&'a str),
+    /// Jump to the label at the index held by the register, or to `default`
+    /// if there is none.
+    Switch {
+        reg: Register,
+        cases: Cases<'a>,
+        default: &'a str,
+    },
     Calculate(CalcOp),
     Random { dst: Register, min: i32, max: i32 },
     Yield,
-    Call { offset_inc: i32, label: &'a str },
+    Sleep(Delay),
+    /// Start the label in a new thread, its index is written into `R0`.
+    Spawn(&'a str),
+    /// Wait for the thread whose index the register holds to finish.
+    Join(Register),
+    /// The arguments, if given, are stored at the start of the new frame.
+    Call {
+        offset_inc: i32,
+        label: &'a str,
+        args: Option<Args<'a>>,
+    },
+    /// `call` with the frame placed at the stack pointer.
+    StackCall {
+        label: &'a str,
+        args: Option<Args<'a>>,
+    },
+    Push(Register),
+    Pop(Register),
+    /// Load the memory cell whose address the register holds into `R0`.
+    LoadPtr(Register),
+    /// Store `R0` into the memory cell whose address the register holds.
+    StorePtr(Register),
+    /// Take a block of the heap with the size in `R0`, its address, or 0 if
+    /// there is no room, is written into `R0`.
+    Alloc,
+    /// Give back the block of the heap whose address is in `R0`.
+    Free,
+    /// Make the bank in `R0` the current bank.
+    Bank,
+    /// Load the id of a label into the register, for `IndirectCall`.
+    Addr { dst: Register, label: &'a str },
+    /// `Call` the label whose id the register holds.
+    IndirectCall { offset_inc: i32, reg: Register },
     Return,
     Debug { line: usize, info: &'a str },
+    /// Print the registers and the cells from `MEM_OFFSET` on.
+    DebugDump { line: usize },
     Log(&'a str),
 }
+
+impl<'a> Instruction<'a> {
+    /// The labels this instruction may transfer control to, including the
+    /// label whose id `addr` takes.
+    pub fn labels(&self) -> Vec<&'a str> {
+        match *self {
+            Instruction::Branch(label)
+            | Instruction::BranchIf(label)
+            | Instruction::BranchIfNot(label)
+            | Instruction::Call { label, .. }
+            | Instruction::StackCall { label, .. }
+            | Instruction::Addr { label, .. }
+            | Instruction::Spawn(label) => vec![label],
+            Instruction::Switch { cases, default, .. } => cases.iter().chain([default]).collect(),
+            _ => Vec::new(),
+        }
+    }
+}
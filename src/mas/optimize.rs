@@ -0,0 +1,480 @@
+use crate::manifest::BuildConfig;
+
+use super::{CalcOp, CmpOp, ExprCmpIn, Instruction, Register, VirtualMachine};
+
+const REGISTERS: [Register; 4] = [Register::R0, Register::R1, Register::R2, Register::R3];
+
+/// Set of registers, one bit for each.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Regs(u8);
+
+impl Regs {
+    const NONE: Regs = Regs(0);
+    const ALL: Regs = Regs(0b1111);
+
+    fn of(regs: &[Register]) -> Self {
+        Regs(regs.iter().fold(0, |bits, reg| bits | 1 << *reg as u8))
+    }
+
+    fn intersects(self, other: Regs) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    fn union(self, other: Regs) -> Self {
+        Regs(self.0 | other.0)
+    }
+
+    fn without(self, other: Regs) -> Self {
+        Regs(self.0 & !other.0)
+    }
+}
+
+impl<'a> VirtualMachine<'a> {
+    /// Optimize the instructions of every label. Level 0 keeps the code as
+    /// written, level 1 removes redundant instructions in adjacent pairs, and
+    /// level 2 additionally inlines labels, folds constants and removes
+    /// instructions whose results are never used.
+    pub fn optimize(&mut self, config: &BuildConfig) {
+        let level = config.opt_level;
+        if level >= 2 {
+            self.inline(config);
+        }
+
+        for function in self.blocks.values_mut() {
+            let code = &mut function.instructions;
+            loop {
+                let before = code.len();
+                let mut changed = level >= 1 && peephole(code);
+                if level >= 2 {
+                    changed |= fold_constants(code);
+                    changed |= remove_dead(code);
+                }
+                if !changed && code.len() == before {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Whether running `code` overwrites `reg` before reading it, following
+    /// unconditional branches. Gives `false` where it cannot tell, including
+    /// the end of a label, after which the registers may be inspected.
+    pub(super) fn overwritten_before_read(&self, code: &[Instruction<'a>], reg: Register) -> bool {
+        let reg = Regs::of(&[reg]);
+        let mut visited = Vec::new();
+        let mut code = code;
+
+        loop {
+            let mut next = None;
+
+            for inst in code {
+                match *inst {
+                    // both start by writing the return block id into R0
+                    Instruction::Call { .. } | Instruction::Return => {
+                        return reg == Regs::of(&[Register::R0]);
+                    }
+                    // reads the pointer before writing the return block id
+                    Instruction::IndirectCall { reg: ptr, .. } => {
+                        return reg == Regs::of(&[Register::R0]) && ptr != Register::R0;
+                    }
+                    Instruction::Branch(label) => {
+                        next = Some(label);
+                        break;
+                    }
+                    _ => {}
+                }
+
+                let (reads, writes) = effects(inst);
+                if reads.intersects(reg) {
+                    return false;
+                }
+                if writes.intersects(reg) {
+                    return true;
+                }
+            }
+
+            match next.and_then(|label| self.blocks.get(label)) {
+                Some(function) if !visited.contains(&function.name) => {
+                    visited.push(function.name);
+                    code = &function.instructions;
+                }
+                _ => return false,
+            }
+        }
+    }
+}
+
+/// Registers read and written by an instruction. Instructions that leave the
+/// current straight-line code read every register.
+fn effects(inst: &Instruction) -> (Regs, Regs) {
+    use Register::*;
+
+    match *inst {
+        Instruction::Move { dst, src } => (Regs::of(&[src]), Regs::of(&[dst])),
+        Instruction::Set { dst, .. }
+        | Instruction::Random { dst, .. }
+        | Instruction::Addr { dst, .. } => (Regs::NONE, Regs::of(&[dst])),
+        Instruction::AddConst { dst, .. } => (Regs::of(&[dst]), Regs::of(&[dst])),
+        Instruction::Load { .. } => (Regs::NONE, Regs::of(&[R0])),
+        Instruction::Push(reg) => (Regs::of(&[reg]), Regs::NONE),
+        Instruction::Pop(reg) => (Regs::NONE, Regs::of(&[reg])),
+        Instruction::Store { .. } => (Regs::of(&[R0]), Regs::NONE),
+        Instruction::LoadPtr(reg) => (Regs::of(&[reg]), Regs::of(&[R0])),
+        Instruction::StorePtr(reg) => (Regs::of(&[R0, reg]), Regs::NONE),
+        Instruction::Alloc => (Regs::of(&[R0]), Regs::of(&[R0])),
+        Instruction::Free | Instruction::Bank => (Regs::of(&[R0]), Regs::NONE),
+        Instruction::Compare(_) | Instruction::Calculate(_) => {
+            (Regs::of(&[R0, R1]), Regs::of(&[R0]))
+        }
+        Instruction::CompareIn { .. } => (Regs::of(&[R0]), Regs::of(&[R0])),
+        Instruction::Debug { .. } | Instruction::Log(_) => (Regs::NONE, Regs::NONE),
+        // prints every register
+        Instruction::DebugDump { .. } => (Regs::of(&[R0, R1, R2, R3]), Regs::NONE),
+        // the new thread starts with a copy of the registers
+        Instruction::Spawn(_) => (Regs::ALL, Regs::of(&[R0])),
+        // raw commands may do anything to the registers
+        Instruction::RawCommand(_) => (Regs::ALL, Regs::ALL),
+        Instruction::Branch(_)
+        | Instruction::BranchIf(_)
+        | Instruction::BranchIfNot(_)
+        | Instruction::Switch { .. }
+        | Instruction::Call { .. }
+        | Instruction::StackCall { .. }
+        | Instruction::IndirectCall { .. }
+        | Instruction::Return
+        | Instruction::Yield
+        | Instruction::Sleep(_)
+        | Instruction::Join(_) => (Regs::ALL, Regs::ALL),
+    }
+}
+
+/// Whether an instruction does nothing but write its registers.
+fn is_pure(inst: &Instruction) -> bool {
+    matches!(
+        inst,
+        Instruction::Move { .. }
+            | Instruction::Set { .. }
+            | Instruction::AddConst { .. }
+            | Instruction::Addr { .. }
+            | Instruction::Compare(_)
+            | Instruction::CompareIn { .. }
+            | Instruction::Calculate(_)
+    )
+}
+
+/// Remove redundant instructions by looking at adjacent pairs.
+fn peephole(code: &mut Vec<Instruction>) -> bool {
+    let mut changed = false;
+    let mut i = 0;
+
+    while i < code.len() {
+        // mov X X; or adding 0
+        if matches!(code[i], Instruction::Move { dst, src } if dst == src)
+            || matches!(code[i], Instruction::AddConst { value: 0, .. })
+        {
+            code.remove(i);
+            changed = true;
+            continue;
+        }
+
+        let Some(&next) = code.get(i + 1) else {
+            break;
+        };
+
+        // `true` removes the first instruction, `false` the second
+        let remove = match (code[i], next) {
+            // mov A B; mov B A
+            (Instruction::Move { dst: a, src: b }, Instruction::Move { dst: c, src: d })
+                if a == d && b == c =>
+            {
+                Some(false)
+            }
+            // set X 1; set X 1
+            (Instruction::Set { dst: a, value: x }, Instruction::Set { dst: b, value: y })
+                if a == b && x == y =>
+            {
+                Some(false)
+            }
+            // store 0; load 0
+            (Instruction::Store { addr: a }, Instruction::Load { addr: b }) if a == b => {
+                Some(false)
+            }
+            // load 0; store 0
+            (Instruction::Load { addr: a }, Instruction::Store { addr: b }) if a == b => {
+                Some(false)
+            }
+            // the first result is overwritten before being read
+            (first, second) if is_pure(&first) => {
+                let (_, written) = effects(&first);
+                let (reads, overwritten) = effects(&second);
+                (written.without(overwritten) == Regs::NONE && !written.intersects(reads))
+                    .then_some(true)
+            }
+            (Instruction::Load { .. }, Instruction::Load { .. }) => Some(true),
+            _ => None,
+        };
+
+        match remove {
+            Some(first) => {
+                code.remove(if first { i } else { i + 1 });
+                changed = true;
+                i = i.saturating_sub(1);
+            }
+            None => i += 1,
+        }
+    }
+
+    changed
+}
+
+/// Replace computations on registers with known values by `set`, and
+/// `set R1 x; calc +` by an addition of the constant.
+fn fold_constants(code: &mut [Instruction]) -> bool {
+    let mut known: [Option<i32>; 4] = [None; 4];
+    let mut changed = false;
+
+    for inst in code.iter_mut() {
+        let value = |reg: Register| known[reg as usize];
+
+        let folded = match *inst {
+            Instruction::Calculate(opr) => match (value(Register::R0), value(Register::R1)) {
+                (Some(a), Some(b)) => calculate(opr, a, b).map(|value| Instruction::Set {
+                    dst: Register::R0,
+                    value,
+                }),
+                (None, Some(b)) => add_const(opr, b).map(|value| Instruction::AddConst {
+                    dst: Register::R0,
+                    value,
+                }),
+                _ => None,
+            },
+            Instruction::Compare(opr) => match (value(Register::R0), value(Register::R1)) {
+                (Some(a), Some(b)) => Some(Instruction::Set {
+                    dst: Register::R0,
+                    value: compare(opr, a, b) as i32,
+                }),
+                _ => None,
+            },
+            Instruction::CompareIn { not, opr } => value(Register::R0).map(|a| {
+                let matched = match opr {
+                    ExprCmpIn::Value(v) => a == v,
+                    ExprCmpIn::Range(lb, ub) => {
+                        lb.is_none_or(|lb| lb <= a) && ub.is_none_or(|ub| a <= ub)
+                    }
+                };
+                Instruction::Set {
+                    dst: Register::R0,
+                    value: (matched != not) as i32,
+                }
+            }),
+            Instruction::Move { dst, src } => value(src).map(|value| Instruction::Set { dst, value }),
+            Instruction::AddConst { dst, value: b } => value(dst)
+                .and_then(|a| a.checked_add(b))
+                .map(|value| Instruction::Set { dst, value }),
+            _ => None,
+        };
+
+        if let Some(folded) = folded {
+            *inst = folded;
+            changed = true;
+        }
+
+        match *inst {
+            Instruction::Set { dst, value } => known[dst as usize] = Some(value),
+            inst => {
+                let (_, written) = effects(&inst);
+                for reg in REGISTERS {
+                    if written.intersects(Regs::of(&[reg])) {
+                        known[reg as usize] = None;
+                    }
+                }
+            }
+        }
+    }
+
+    changed
+}
+
+// mirrors the integer semantics of scoreboard operations, gives up where
+// they are not obvious
+fn calculate(opr: CalcOp, a: i32, b: i32) -> Option<i32> {
+    match opr {
+        CalcOp::Add => a.checked_add(b),
+        CalcOp::Sub => a.checked_sub(b),
+        CalcOp::Mul => a.checked_mul(b),
+        CalcOp::Div if a >= 0 && b > 0 => Some(a / b),
+        CalcOp::Rem if a >= 0 && b > 0 => Some(a % b),
+        CalcOp::Div | CalcOp::Rem => None,
+        CalcOp::Min => Some(a.min(b)),
+        CalcOp::Max => Some(a.max(b)),
+    }
+}
+
+fn add_const(opr: CalcOp, b: i32) -> Option<i32> {
+    match opr {
+        CalcOp::Add => Some(b),
+        CalcOp::Sub => b.checked_neg(),
+        _ => None,
+    }
+}
+
+fn compare(opr: CmpOp, a: i32, b: i32) -> bool {
+    match opr {
+        CmpOp::LessThan => a < b,
+        CmpOp::GreaterThan => a > b,
+        CmpOp::LessEq => a <= b,
+        CmpOp::GreaterEq => a >= b,
+        CmpOp::Equals => a == b,
+        CmpOp::NotEquals => a != b,
+    }
+}
+
+/// Remove pure instructions whose results are never read. Registers are
+/// assumed to be read after the end of the label.
+fn remove_dead(code: &mut Vec<Instruction>) -> bool {
+    let mut live = Regs::ALL;
+    let mut dead = Vec::new();
+
+    for (index, inst) in code.iter().enumerate().rev() {
+        let (reads, writes) = effects(inst);
+        if is_pure(inst) && !writes.intersects(live) {
+            dead.push(index);
+            continue;
+        }
+        live = live.without(writes).union(reads);
+    }
+
+    for index in &dead {
+        code.remove(*index);
+    }
+    !dead.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{bootstrap::ERROR, emu, manifest::BuildConfig, mas::VirtualMachine};
+
+    /// Instructions of `main` once `source` is optimized at `opt_level`.
+    fn optimized(source: &str, opt_level: u8) -> Vec<String> {
+        let config = BuildConfig {
+            opt_level,
+            ..Default::default()
+        };
+        let mut vm = VirtualMachine::new();
+        vm.parse_source(source).unwrap();
+        vm.pass_arguments().unwrap();
+        vm.optimize(&config);
+        vm.blocks["main"]
+            .instructions
+            .iter()
+            .map(|inst| format!("{inst:?}"))
+            .collect()
+    }
+
+    /// Run `source` at every optimization level, with and without checks,
+    /// and compare the first cells and the messages with the unoptimized
+    /// program. The cells must be `expected`.
+    fn assert_preserved(source: &str, expected: &[i32]) {
+        for checks in [true, false] {
+            let mut unoptimized = None;
+            for opt_level in 0..=2 {
+                let config = BuildConfig {
+                    opt_level,
+                    checks,
+                    ..Default::default()
+                };
+                let emu = emu::run(source, &config, 10);
+                let cells: Vec<i32> = (0..expected.len())
+                    .map(|nth| emu.cell(&config.memory(), nth))
+                    .collect();
+                assert_eq!(cells, expected, "cells at -O{opt_level}, checks {checks}");
+                assert_eq!(emu.reg(ERROR), 0, "trap at -O{opt_level}, checks {checks}");
+
+                let said = unoptimized.get_or_insert_with(|| emu.said.clone());
+                assert_eq!(
+                    &emu.said, said,
+                    "messages at -O{opt_level}, checks {checks}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn folds_constants() {
+        let source = "main:\nset R0 2\nset R1 3\ncalc +\nstore 0\nset R1 4\ncalc *\nstore 1\n\
+            set R1 3\ncalc -\nstore 2\nset R1 7\ncmp >\nstore 3\n";
+        assert_preserved(source, &[5, 20, 17, 1]);
+
+        let code = optimized(source, 2);
+        assert!(
+            !code.iter().any(|inst| inst.starts_with("Calculate")),
+            "{code:?}"
+        );
+        assert!(
+            !code.iter().any(|inst| inst.starts_with("Compare")),
+            "{code:?}"
+        );
+    }
+
+    #[test]
+    fn leaves_division_of_negative_values() {
+        // scoreboard division rounds towards negative infinity
+        let source = "main:\nset R0 -7\nset R1 2\ncalc /\nstore 0\nset R0 -7\ncalc %\nstore 1\n";
+        assert_preserved(source, &[-4, 1]);
+        assert!(optimized(source, 2)
+            .iter()
+            .any(|inst| inst.starts_with("Calculate")));
+    }
+
+    #[test]
+    fn removes_dead_results() {
+        let source = "main:\nset R0 5\nset R0 6\nstore 0\nset R1 1\nset R1 2\nmov R0 R1\nstore 1\n";
+        assert_preserved(source, &[6, 2]);
+        assert!(optimized(source, 2).len() < optimized(source, 0).len());
+    }
+
+    #[test]
+    fn removes_redundant_pairs() {
+        let source = "main:\nset R0 7\nmov R0 R0\nmov R1 R0\nmov R0 R1\nstore 0\nload 0\n\
+            set R1 1\ncalc +\nstore 1\nload 1\nstore 1\nset R2 3\nset R2 3\nmov R0 R2\nstore 2\n";
+        assert_preserved(source, &[7, 8, 3]);
+
+        let unoptimized = optimized(source, 0);
+        let code = optimized(source, 1);
+        assert!(code.len() < unoptimized.len(), "{code:?}");
+        assert!(
+            !code.iter().any(|inst| inst.starts_with("Load")),
+            "{code:?}"
+        );
+    }
+
+    #[test]
+    fn keeps_values_read_after_a_branch() {
+        // R1 is only read by the label the branch jumps to
+        let source = "main:\nset R1 7\nset R0 3\ncmpin 3\nbi copy\nset R0 1\nstore 1\nret\n\n\
+            copy:\nmov R0 R1\nstore 0\nset R1 0\nret\n";
+        assert_preserved(source, &[7, 0]);
+
+        // the result of the comparison is still read by the other path
+        let source = "main:\nset R0 4\ncmpin 3\nbn other\nstore 0\nret\n\n\
+            other:\nstore 1\nset R0 2\nstore 2\nret\n";
+        assert_preserved(source, &[0, 0, 2]);
+    }
+
+    #[test]
+    fn keeps_stores_read_through_pointers() {
+        let source = "main:\nset R0 9\nstore 3\nset R1 3\nloadp R1\nstore 4\n\
+            set R0 4\nset R1 5\nstorep R1\nload 5\nstore 6\nset R0 1\nstore 5\nload 5\nstore 7\n";
+        assert_preserved(source, &[0, 0, 0, 9, 9, 1, 4, 1]);
+
+        let code = optimized(source, 2);
+        assert!(
+            code.iter().any(|inst| inst == "Store { addr: 3 }"),
+            "{code:?}"
+        );
+        assert!(
+            code.iter().any(|inst| inst == "Load { addr: 5 }"),
+            "{code:?}"
+        );
+    }
+}
@@ -1,36 +1,40 @@
-use std::collections::{hash_map::Entry, HashMap};
+use std::collections::btree_map::Entry;
 
 use anyhow::{anyhow, Result};
 use nom::{
     branch::alt,
     bytes::complete::{is_not, tag},
-    character::complete::{i32 as parse_i32, space0, space1},
-    combinator::{eof, map, opt, rest, value},
+    character::complete::{i32 as parse_i32, space0, space1, u32 as parse_u32},
+    combinator::{eof, map, map_opt, opt, recognize, rest, value, verify},
     error::{Error, ErrorKind},
-    multi::{fold_many0, separated_list0},
+    multi::{fold_many0, many0, separated_list0},
     sequence::{delimited, pair, preceded, separated_pair, terminated, tuple, Tuple},
     IResult, InputTakeAtPosition, Parser,
 };
 
-use super::{CalcOp, CmpOp, ExprCmpIn, Function, Instruction, Register, VirtualMachine};
+use super::{
+    Args, CalcOp, Cases, CmpOp, Delay, ExprCmpIn, Function, Hook, Instruction, Register,
+    VirtualMachine,
+};
 
 impl<'a> VirtualMachine<'a> {
-    pub fn parse(text: &'a str) -> Result<Self> {
-        let mut blocks: HashMap<&str, Function> = HashMap::new();
-        let mut current_label = None;
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parse one more source file into this machine. Labels are shared
+    /// between all source files.
+    pub fn parse_source(&mut self, text: &'a str) -> Result<()> {
+        let blocks = &mut self.blocks;
+        let mut current_label: Option<&mut Function> = None;
 
         for (line_index, line) in text.lines().enumerate() {
-            if comment(line).is_ok() {
+            let args = current_label
+                .as_deref()
+                .map_or(&[][..], |f| f.args.as_slice());
+            let Some(loi) = parse_line(line, line_index + 1, args)? else {
                 continue;
-            }
-
-            let line_number = line_index + 1;
-
-            let (_, (loi, ())) = pair(
-                alt((parse_func_sig, parse_instruction(line_number))),
-                comment,
-            )(line)
-            .map_err(|e| anyhow!("cannot parse code at line {line_number}: {e}"))?;
+            };
 
             match loi {
                 FuncOrInst::Function(func) => {
@@ -53,44 +57,90 @@ impl<'a> VirtualMachine<'a> {
             }
         }
 
-        Ok(VirtualMachine { blocks })
+        Ok(())
     }
 }
 
-enum FuncOrInst<'a> {
+pub enum FuncOrInst<'a> {
     Function(Function<'a>),
     Instruction(Instruction<'a>),
 }
 
-fn parse_func_sig(input: &str) -> IResult<&str, FuncOrInst> {
+/// Parse a single line of source, `None` for blank and comment lines.
+/// Names in the result are slices of `line`. `args` are the parameters of
+/// the label the line is under, which `load` and `store` may name.
+pub fn parse_line<'a>(
+    line: &'a str,
+    line_number: usize,
+    args: &[&str],
+) -> Result<Option<FuncOrInst<'a>>> {
+    if comment(line).is_ok() {
+        return Ok(None);
+    }
+
+    let (_, (loi, ())) = pair(
+        alt((parse_func_sig, parse_instruction(line_number, args))),
+        comment,
+    )(line)
+    .map_err(|e| anyhow!("cannot parse code at line {line_number}: {e}"))?;
+
+    Ok(Some(loi))
+}
+
+fn parse_func_sig(input: &str) -> IResult<&str, FuncOrInst<'_>> {
     map(
         preceded(
             space0,
             terminated(
-                pair(
+                tuple((
+                    many0(terminated(hook, space1)),
                     ident,
-                    delimited(
-                        pair(space0, tag("(")),
-                        separated_list0(tag(","), delimited(space0, ident, space0)),
-                        tag(")"),
+                    map(
+                        opt(delimited(
+                            pair(space0, tag("(")),
+                            separated_list0(tag(","), delimited(space0, ident, space0)),
+                            tag(")"),
+                        )),
+                        Option::unwrap_or_default,
                     ),
-                ),
+                )),
                 preceded(space0, tag(":")),
             ),
         ),
-        |(name, args)| {
+        |(hooks, name, args)| {
             FuncOrInst::Function(Function {
                 name,
                 args,
+                hooks,
                 instructions: Vec::new(),
             })
         },
     )(input)
 }
 
-fn parse_instruction<'a>(
+fn hook(input: &str) -> IResult<&str, Hook> {
+    alt((
+        value(Hook::Load, tag("@on_load")),
+        value(Hook::Tick, tag("@on_tick")),
+        value(Hook::Trap, tag("@on_trap")),
+        map(
+            delimited(
+                tag("@every("),
+                verify(delimited(space0, parse_u32, space0), |ticks| *ticks > 0),
+                tag(")"),
+            ),
+            Hook::Every,
+        ),
+    ))(input)
+}
+
+fn parse_instruction<'a, 'b>(
     line_number: usize,
-) -> impl FnMut(&'a str) -> IResult<&'a str, FuncOrInst> {
+    args: &'b [&'b str],
+) -> impl FnMut(&'a str) -> IResult<&'a str, FuncOrInst<'a>> + 'b
+where
+    'a: 'b,
+{
     let cmd = command_format("cmd", (ls(expr_str),), |(cmd,)| {
         Instruction::RawCommand(cmd)
     });
@@ -103,11 +153,11 @@ fn parse_instruction<'a>(
         Instruction::Set { dst, value }
     });
 
-    let load = command_format("load", (ls(parse_i32),), |(addr,)| Instruction::Load {
+    let load = command_format("load", (ls(slot(args)),), |(addr,)| Instruction::Load {
         addr,
     });
 
-    let store = command_format("store", (ls(parse_i32),), |(addr,)| Instruction::Store {
+    let store = command_format("store", (ls(slot(args)),), |(addr,)| Instruction::Store {
         addr,
     });
 
@@ -126,6 +176,20 @@ fn parse_instruction<'a>(
 
     let bi = command_format("bi", (ls(ident),), |(label,)| Instruction::BranchIf(label));
 
+    let bn = command_format("bn", (ls(ident),), |(label,)| {
+        Instruction::BranchIfNot(label)
+    });
+
+    let switch = command_format(
+        "switch",
+        (ls(register), ls(cases), ls(tag("default")), ls(ident)),
+        |(reg, cases, _, default)| Instruction::Switch {
+            reg,
+            cases,
+            default,
+        },
+    );
+
     let calc = command_format("calc", (ls(calc_operator),), |(opr,)| {
         Instruction::Calculate(opr)
     });
@@ -136,12 +200,54 @@ fn parse_instruction<'a>(
         |(dst, min, max)| Instruction::Random { dst, min, max },
     );
 
-    let yield_now = command_format("yield", (ls(tag("yield")),), |_| Instruction::Yield);
+    let yield_now = command_format("yield", (), |()| Instruction::Yield);
+
+    let sleep = command_format("sleep", (ls(delay),), |(delay,)| Instruction::Sleep(delay));
+
+    let spawn = command_format("spawn", (ls(ident),), |(label,)| Instruction::Spawn(label));
+
+    let join = command_format("join", (ls(register),), |(reg,)| Instruction::Join(reg));
+
+    let call = command_format(
+        "call",
+        (ls(parse_i32), ls(ident), opt(arguments)),
+        |(offset_inc, label, args)| Instruction::Call {
+            offset_inc,
+            label,
+            args,
+        },
+    );
+
+    let stack_call = command_format("call", (ls(ident), opt(arguments)), |(label, args)| {
+        Instruction::StackCall { label, args }
+    });
+
+    let push = command_format("push", (ls(register),), |(reg,)| Instruction::Push(reg));
+
+    let pop = command_format("pop", (ls(register),), |(reg,)| Instruction::Pop(reg));
+
+    let loadp = command_format("loadp", (ls(register),), |(reg,)| Instruction::LoadPtr(reg));
+
+    let storep = command_format("storep", (ls(register),), |(reg,)| {
+        Instruction::StorePtr(reg)
+    });
+
+    let alloc = command_format("alloc", (), |()| Instruction::Alloc);
 
-    let call = command_format("call", (ls(parse_i32), ls(ident)), |(offset_inc, label)| {
-        Instruction::Call { offset_inc, label }
+    let free = command_format("free", (), |()| Instruction::Free);
+
+    let bank = command_format("bank", (), |()| Instruction::Bank);
+
+    let addr = command_format("addr", (ls(register), ls(ident)), |(dst, label)| {
+        Instruction::Addr { dst, label }
     });
 
+    let icall = command_format(
+        "icall",
+        (ls(parse_i32), ls(register)),
+        |(offset_inc, reg)| Instruction::IndirectCall { offset_inc, reg },
+    );
+
     let ret = command_format("ret", (), |()| Instruction::Return);
 
     let debug = command_format("debug", (ls(expr_str),), move |(info,)| {
@@ -151,13 +257,39 @@ fn parse_instruction<'a>(
         }
     });
 
+    let debug_dump = command_format("debug", (ls(tag("dump")),), move |_| {
+        Instruction::DebugDump { line: line_number }
+    });
+
     let log = command_format("log", (ls(expr_str),), |(msg,)| Instruction::Log(msg));
 
     map(
         terminated(
             alt((
-                cmd, mov, set, load, store, cmp, cmpin, b, bi, calc, rand, yield_now, call, ret,
-                debug, log,
+                cmd,
+                mov,
+                set,
+                // `load` and `store` also start the absolute accesses
+                alt((load, store, loadp, storep)),
+                cmp,
+                cmpin,
+                b,
+                bi,
+                bn,
+                switch,
+                calc,
+                rand,
+                // instructions that may stop until a later tick
+                alt((yield_now, sleep, spawn, join)),
+                // the offset is optional
+                alt((call, stack_call)),
+                alt((push, pop)),
+                alt((alloc, free, bank)),
+                addr,
+                icall,
+                ret,
+                alt((debug_dump, debug)),
+                log,
             )),
             comment,
         ),
@@ -169,7 +301,7 @@ fn command_format<'a, P, O, F>(
     t: &'a str,
     parser: P,
     mapper: F,
-) -> impl FnMut(&'a str) -> IResult<&'a str, Instruction>
+) -> impl FnMut(&'a str) -> IResult<&'a str, Instruction<'a>>
 where
     P: Tuple<&'a str, O, Error<&'a str>>,
     F: FnMut(O) -> Instruction<'a>,
@@ -228,6 +360,49 @@ fn register(input: &str) -> IResult<&str, Register> {
     ))(input)
 }
 
+fn cases(input: &str) -> IResult<&str, Cases<'_>> {
+    map(
+        delimited(
+            tag("["),
+            recognize(separated_list0(tag(","), delimited(space0, ident, space0))),
+            tag("]"),
+        ),
+        Cases,
+    )(input)
+}
+
+/// A frame address, or the name of a parameter for its slot.
+fn slot<'a, 'b>(args: &'b [&'b str]) -> impl FnMut(&'a str) -> IResult<&'a str, i32> + 'b
+where
+    'a: 'b,
+{
+    alt((
+        parse_i32,
+        map_opt(ident, |name| {
+            let index = args.iter().position(|arg| *arg == name)?;
+            i32::try_from(index).ok()
+        }),
+    ))
+}
+
+fn arguments(input: &str) -> IResult<&str, Args<'_>> {
+    map(
+        delimited(
+            pair(space0, tag("(")),
+            recognize(separated_list0(
+                tag(","),
+                delimited(space0, register, space0),
+            )),
+            tag(")"),
+        ),
+        Args,
+    )(input)
+}
+
+fn delay(input: &str) -> IResult<&str, Delay> {
+    alt((map(register, Delay::Register), map(parse_i32, Delay::Ticks)))(input)
+}
+
 fn cmp_operator(input: &str) -> IResult<&str, CmpOp> {
     alt((
         value(CmpOp::Equals, tag("==")),
@@ -257,6 +432,6 @@ fn cmp_in(input: &str) -> IResult<&str, ExprCmpIn> {
             separated_pair(opt(parse_i32), tag(".."), opt(parse_i32)),
             |(lb, ub)| ExprCmpIn::Range(lb, ub),
         ),
-        map(parse_i32, |val| ExprCmpIn::Value(val)),
+        map(parse_i32, ExprCmpIn::Value),
     ))(input)
 }
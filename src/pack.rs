@@ -0,0 +1,196 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use serde_json::json;
+
+use crate::manifest::PackageInfo;
+
+/// Lists every file written by the last build, relative to the pack root.
+const RECORD_NAME: &str = ".mcvm_generated";
+
+/// Files of a behavior pack, collected in memory before touching the disk.
+#[derive(Default)]
+pub struct Output {
+    files: BTreeMap<PathBuf, String>,
+}
+
+/// What [`Output::sync`] changed on the disk.
+pub struct SyncStats {
+    pub written: usize,
+    pub removed: usize,
+}
+
+impl Output {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `functions/<name>.mcfunction`.
+    pub fn add_function(&mut self, name: &str, content: impl Into<String>) {
+        self.add_file(format!("functions/{name}.mcfunction"), content);
+    }
+
+    /// Content of `functions/<name>.mcfunction`, if it was added.
+    #[cfg(test)]
+    pub fn function(&self, name: &str) -> Option<&str> {
+        let path = PathBuf::from(format!("functions/{name}.mcfunction"));
+        self.files.get(&path).map(String::as_str)
+    }
+
+    /// Add a file relative to the pack root.
+    pub fn add_file(&mut self, path: impl Into<PathBuf>, content: impl Into<String>) {
+        let path = path.into();
+        if self.files.insert(path.clone(), content.into()).is_some() {
+            panic!("file `{}` is generated twice", path.display());
+        }
+    }
+
+    /// Write the collected files under `pack_root`. Files whose content did
+    /// not change are left untouched, and files generated by the previous
+    /// build but not by this one are removed. Files that were never
+    /// generated by mcvm are never touched.
+    pub fn sync(&self, pack_root: &Path) -> Result<SyncStats> {
+        let mut stats = SyncStats {
+            written: 0,
+            removed: 0,
+        };
+
+        for (path, content) in &self.files {
+            let path = pack_root.join(path);
+            match fs::read(&path) {
+                Ok(old) if old == content.as_bytes() => continue,
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(path, content)?;
+            stats.written += 1;
+        }
+
+        let record_path = pack_root.join(RECORD_NAME);
+        let old_record = match fs::read_to_string(&record_path) {
+            Ok(record) => record,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        for stale in old_record
+            .lines()
+            .map(PathBuf::from)
+            .filter(|path| !self.files.contains_key(path))
+            .collect::<BTreeSet<_>>()
+        {
+            let path = pack_root.join(&stale);
+            match fs::remove_file(&path) {
+                Ok(()) => stats.removed += 1,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+            remove_empty_parents(pack_root, &path);
+        }
+
+        let record: String = self
+            .files
+            .keys()
+            .map(|path| format!("{}\n", path.display()))
+            .collect();
+        if record != old_record {
+            fs::write(record_path, record)?;
+        }
+
+        Ok(stats)
+    }
+}
+
+fn remove_empty_parents(pack_root: &Path, path: &Path) {
+    let mut dir = path.parent();
+    while let Some(d) = dir.filter(|d| *d != pack_root) {
+        // fails when the folder is not empty
+        if fs::remove_dir(d).is_err() {
+            break;
+        }
+        dir = d.parent();
+    }
+}
+
+/// Add the `manifest.json` of the behavior pack.
+pub fn add_manifest(output: &mut Output, package: &PackageInfo) -> Result<()> {
+    let header_uuid = package
+        .uuid
+        .clone()
+        .unwrap_or_else(|| derive_uuid(&package.name, "header"));
+    let module_uuid = package
+        .module_uuid
+        .clone()
+        .unwrap_or_else(|| derive_uuid(&package.name, "data"));
+
+    let manifest = json!({
+        "format_version": 2,
+        "header": {
+            "name": package.name,
+            "description": package.description,
+            "uuid": header_uuid,
+            "version": package.version,
+            "min_engine_version": package.min_engine_version,
+        },
+        "modules": [
+            {
+                "type": "data",
+                "uuid": module_uuid,
+                "version": package.version,
+            }
+        ],
+    });
+
+    output.add_file("manifest.json", serde_json::to_string_pretty(&manifest)?);
+    Ok(())
+}
+
+/// Add `functions/tick.json`, which runs `functions` on every game tick.
+pub fn add_tick(output: &mut Output, functions: &[&str]) -> Result<()> {
+    let tick = json!({ "values": functions });
+    output.add_file("functions/tick.json", serde_json::to_string_pretty(&tick)?);
+    Ok(())
+}
+
+// uuids must not change between builds, otherwise the game treats every
+// build as a different pack
+fn derive_uuid(name: &str, salt: &str) -> String {
+    let hi = stable_hash(name, salt);
+    let lo = fnv1a(hi, name, salt);
+
+    // version 4 layout, variant 1
+    let hi = hi & !0xf000 | 0x4000;
+    let lo = lo & !(0b11 << 62) | 0b10 << 62;
+
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        hi >> 32,
+        hi >> 16 & 0xffff,
+        hi & 0xffff,
+        lo >> 48,
+        lo & 0xffff_ffff_ffff
+    )
+}
+
+/// A hash that never changes between builds or compiler versions.
+pub fn stable_hash(name: &str, salt: &str) -> u64 {
+    fnv1a(0xcbf2_9ce4_8422_2325, name, salt)
+}
+
+fn fnv1a(seed: u64, name: &str, salt: &str) -> u64 {
+    name.bytes()
+        .chain([0])
+        .chain(salt.bytes())
+        .fold(seed, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+        })
+}